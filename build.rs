@@ -0,0 +1,25 @@
+use std::process::Command;
+
+fn main() {
+    // Railway doesn't ship the .git directory, but exposes the commit as an env var
+    let git_sha = std::env::var("RAILWAY_GIT_COMMIT_SHA")
+        .ok()
+        .or_else(|| {
+            Command::new("git")
+                .args(["rev-parse", "HEAD"])
+                .output()
+                .ok()
+                .filter(|output| output.status.success())
+                .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_string())
+        })
+        .unwrap_or("unknown".into());
+
+    println!("cargo:rustc-env=GIT_SHA={:}", git_sha);
+    println!(
+        "cargo:rustc-env=BUILT_AT={:}",
+        chrono::Utc::now().to_rfc3339()
+    );
+    println!("cargo:rerun-if-env-changed=RAILWAY_GIT_COMMIT_SHA");
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs");
+}
@@ -0,0 +1,54 @@
+use std::{
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+/// Ttl of the first poll finding nothing playing
+const MIN_TTL_MS: i64 = 10 * 1000;
+
+/// How long "nothing playing" is cached. Doubles with every poll in a row that
+/// found nothing playing, up to `max_ttl_ms`, so an idle account isn't asked
+/// every few seconds all night. Shared with the metrics reading the level.
+#[derive(Clone)]
+pub struct IdleBackoff {
+    level: Arc<AtomicU32>,
+    max_ttl_ms: i64,
+}
+
+impl IdleBackoff {
+    pub fn new(max_ttl: Duration) -> Self {
+        Self {
+            level: Arc::new(AtomicU32::new(0)),
+            max_ttl_ms: (max_ttl.as_millis() as i64).max(MIN_TTL_MS),
+        }
+    }
+
+    /// Backs off one more level, returning the ttl to cache nothing playing for
+    pub fn increase(&self) -> i64 {
+        let level = self.level.load(Ordering::SeqCst);
+        let ttl_ms = self.ttl_ms(level);
+        // Stays at the level reaching the max rather than overflowing the shift
+        if ttl_ms < self.max_ttl_ms {
+            self.level.store(level + 1, Ordering::SeqCst);
+        }
+        ttl_ms
+    }
+
+    /// Something is playing again, the next idle poll starts from the minimum
+    pub fn reset(&self) {
+        self.level.store(0, Ordering::SeqCst);
+    }
+
+    pub fn level(&self) -> u32 {
+        self.level.load(Ordering::SeqCst)
+    }
+
+    fn ttl_ms(&self, level: u32) -> i64 {
+        MIN_TTL_MS
+            .saturating_mul(1 << level.min(16))
+            .min(self.max_ttl_ms)
+    }
+}
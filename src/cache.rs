@@ -0,0 +1,91 @@
+use std::{
+    collections::HashMap,
+    hash::Hash,
+    io::{Read, Write},
+};
+
+use flate2::{read::DeflateDecoder, write::DeflateEncoder, Compression};
+
+/// Small capacity bounded cache, evicting the least recently used entry once
+/// full. Entries expire `ttl` milliseconds after being inserted.
+pub struct LruCache<K, V> {
+    capacity: usize,
+    ttl: i64,
+    uses: u64,
+    entries: HashMap<K, Entry<V>>,
+}
+
+struct Entry<V> {
+    value: V,
+    expires_at: i64,
+    last_used: u64,
+}
+
+impl<K: Eq + Hash + Clone, V: Clone> LruCache<K, V> {
+    pub fn new(capacity: usize, ttl: i64) -> Self {
+        Self {
+            capacity,
+            ttl,
+            uses: 0,
+            entries: HashMap::new(),
+        }
+    }
+
+    pub fn get(&mut self, key: &K) -> Option<V> {
+        let now = chrono::Utc::now().timestamp_millis();
+        self.uses += 1;
+        match self.entries.get_mut(key) {
+            Some(entry) if entry.expires_at > now => {
+                entry.last_used = self.uses;
+                Some(entry.value.clone())
+            }
+            Some(_) => {
+                self.entries.remove(key);
+                None
+            }
+            None => None,
+        }
+    }
+
+    pub fn insert(&mut self, key: K, value: V) {
+        if self.entries.len() >= self.capacity && !self.entries.contains_key(&key) {
+            let least_recently_used = self
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(key, _)| key.clone());
+            if let Some(least_recently_used) = least_recently_used {
+                self.entries.remove(&least_recently_used);
+            }
+        }
+
+        self.uses += 1;
+        self.entries.insert(
+            key,
+            Entry {
+                value,
+                expires_at: chrono::Utc::now().timestamp_millis() + self.ttl,
+                last_used: self.uses,
+            },
+        );
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+}
+
+/// Deflates a response to keep in memory, for caches where memory matters more
+/// than the cpu spent inflating it on every hit
+pub fn compress(bytes: &[u8]) -> Vec<u8> {
+    let mut encoder = DeflateEncoder::new(Vec::new(), Compression::fast());
+    // Writing to a `Vec` can't fail
+    encoder.write_all(bytes).unwrap();
+    encoder.finish().unwrap()
+}
+
+pub fn decompress(bytes: &[u8]) -> std::io::Result<String> {
+    let mut decompressed = String::new();
+    DeflateDecoder::new(bytes).read_to_string(&mut decompressed)?;
+    Ok(decompressed)
+}
@@ -0,0 +1,43 @@
+use std::net::{IpAddr, SocketAddr};
+
+use axum::{
+    extract::ConnectInfo,
+    http::{HeaderMap, Request},
+    middleware::Next,
+    response::Response,
+};
+use tracing::{info_span, Instrument};
+
+/// Address of the client a request came from, for anything keyed on the client
+/// like logs or rate limiting. Uses the socket address, or with `trust_proxy`
+/// the address the proxy appended to `X-Forwarded-For`. Only the last entry is
+/// taken, everything before it was sent by the client and can be spoofed.
+pub fn resolve(
+    headers: &HeaderMap,
+    socket_addr: Option<SocketAddr>,
+    trust_proxy: bool,
+) -> Option<IpAddr> {
+    let forwarded = trust_proxy
+        .then(|| headers.get_all("X-Forwarded-For").iter().next_back())
+        .flatten()
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.rsplit(',').next())
+        .and_then(|ip| ip.trim().parse().ok());
+
+    forwarded.or(socket_addr.map(|addr| addr.ip()))
+}
+
+/// Logs everything while handling the request with the client ip
+pub async fn attach<B>(trust_proxy: bool, request: Request<B>, next: Next<B>) -> Response {
+    let socket_addr = request
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| *addr);
+    let Some(ip) = resolve(request.headers(), socket_addr, trust_proxy) else {
+        return next.run(request).await;
+    };
+
+    next.run(request)
+        .instrument(info_span!("request", client_ip = %ip))
+        .await
+}
@@ -0,0 +1,235 @@
+use std::{env, time::Duration};
+
+use chrono_tz::Tz;
+
+use crate::tls::TlsPaths;
+
+const MAX_TOP_SONGS_FETCH_LIMIT: usize = 50;
+const DEFAULT_CORS_ALLOWED_ORIGINS: [&str; 3] = [
+    "https://finndore.dev",
+    "finnnn.vercel.app",
+    "http://localhost:3000",
+];
+
+pub struct Config {
+    pub spotify_client_id: String,
+    pub spotify_client_secret: String,
+    pub spotify_refresh_token: String,
+    pub external_auth_token: String,
+    pub port: String,
+    /// How many top songs are fetched from and cached in one go
+    pub top_songs_fetch_limit: usize,
+    /// Keeps the cached top songs deflated, less memory for a little cpu on every hit
+    pub compress_cache: bool,
+    /// Most pages followed when collecting the items of a paginated endpoint
+    pub max_pages: usize,
+    /// Leaves out every route changing the connected account's playback or library
+    pub read_only: bool,
+    /// Most plays kept in the in memory history, the oldest are dropped first
+    pub history_max: usize,
+    /// Served over HTTPS when set, plain HTTP otherwise
+    pub tls: Option<TlsPaths>,
+    /// Image returned for albums Spotify has no art for
+    pub fallback_image_url: Option<String>,
+    /// Looks up canvases, `{track_id}` is replaced with the id of the current track
+    pub canvas_provider_url: Option<String>,
+    /// Linked from the page `/` shows browsers
+    pub frontend_url: Option<String>,
+    /// Looks up timed lyrics, `{track_id}` and `{duration_ms}` are replaced with the current track's
+    pub synced_lyrics_provider_url: Option<String>,
+    /// Retries of a failed token refresh, revoked refresh tokens aren't retried
+    pub token_refresh_retries: u32,
+    /// Retries of a player write answered with no active device, which a device waking up gives
+    pub device_wake_retries: u32,
+    /// Timezone the listening stats are bucketed in
+    pub timezone: Tz,
+    /// Origins allowed by CORS, matched against the end of the origin
+    pub cors_allowed_origins: Vec<String>,
+    pub cors_allow_credentials: bool,
+    /// Largest `limit` any list endpoint accepts
+    pub max_items: usize,
+    /// Looks up the active device before player writes to target it explicitly
+    pub refresh_active_device: bool,
+    /// Interval of the keepalive comments on idle `/stream` connections
+    pub sse_keepalive: Duration,
+    /// Whether the client ip is read from `X-Forwarded-For`, only safe behind a proxy setting it
+    pub trust_proxy: bool,
+    /// Most open `/stream` and `/ws` connections, more are rejected with a 503
+    pub max_stream_clients: usize,
+    /// Most Spotify requests in flight at once, more wait for one to finish
+    pub max_concurrent_spotify_requests: usize,
+    /// Longest "nothing playing" is cached for after backing off on an idle account
+    pub idle_backoff_max: Duration,
+    /// Path every route is nested under, like `/api/spot`. Empty serves them from the root.
+    pub path_prefix: String,
+}
+
+impl Config {
+    pub fn from_env() -> Self {
+        let top_songs_fetch_limit = env::var("TOP_SONGS_FETCH_LIMIT")
+            .map(|limit| {
+                limit
+                    .parse::<usize>()
+                    .expect("TOP_SONGS_FETCH_LIMIT must be a number")
+            })
+            .unwrap_or(32);
+        if !(1..=MAX_TOP_SONGS_FETCH_LIMIT).contains(&top_songs_fetch_limit) {
+            panic!(
+                "TOP_SONGS_FETCH_LIMIT must be between 1 and {:}",
+                MAX_TOP_SONGS_FETCH_LIMIT
+            );
+        }
+
+        let max_pages = env::var("MAX_PAGES")
+            .map(|pages| pages.parse::<usize>().expect("MAX_PAGES must be a number"))
+            .unwrap_or(10);
+
+        let history_max = env::var("HISTORY_MAX")
+            .map(|max| max.parse::<usize>().expect("HISTORY_MAX must be a number"))
+            .unwrap_or(500);
+
+        let token_refresh_retries = env::var("TOKEN_REFRESH_RETRIES")
+            .map(|retries| {
+                retries
+                    .parse::<u32>()
+                    .expect("TOKEN_REFRESH_RETRIES must be a number")
+            })
+            .unwrap_or(2);
+
+        // Every retry holds up the other requests, so only a couple
+        let device_wake_retries = env::var("DEVICE_WAKE_RETRY")
+            .map(|retries| {
+                retries
+                    .parse::<u32>()
+                    .ok()
+                    .filter(|retries| *retries <= 2)
+                    .expect("DEVICE_WAKE_RETRY must be 0, 1 or 2")
+            })
+            .unwrap_or(0);
+
+        let timezone = env::var("TZ")
+            .map(|timezone| {
+                timezone
+                    .parse::<Tz>()
+                    .expect("TZ must be an IANA timezone like Europe/London")
+            })
+            .unwrap_or(Tz::UTC);
+
+        let max_items = env::var("MAX_ITEMS")
+            .map(|max| max.parse::<usize>().expect("MAX_ITEMS must be a number"))
+            .unwrap_or(100);
+
+        let sse_keepalive = env::var("SSE_KEEPALIVE_SECS")
+            .map(|secs| {
+                secs.parse::<u64>()
+                    .expect("SSE_KEEPALIVE_SECS must be a number")
+            })
+            .unwrap_or(15);
+
+        let max_stream_clients = env::var("MAX_STREAM_CLIENTS")
+            .map(|max| {
+                max.parse::<usize>()
+                    .expect("MAX_STREAM_CLIENTS must be a number")
+            })
+            .unwrap_or(100);
+
+        let max_concurrent_spotify_requests = env::var("MAX_CONCURRENT_SPOTIFY_REQUESTS")
+            .map(|max| {
+                max.parse::<usize>()
+                    .ok()
+                    .filter(|max| *max > 0)
+                    .expect("MAX_CONCURRENT_SPOTIFY_REQUESTS must be a positive number")
+            })
+            .unwrap_or(8);
+
+        let idle_backoff_max = env::var("IDLE_BACKOFF_MAX_SECS")
+            .map(|secs| {
+                secs.parse::<u64>()
+                    .expect("IDLE_BACKOFF_MAX_SECS must be a number")
+            })
+            .unwrap_or(300);
+
+        let tls = match (env::var("TLS_CERT_PATH"), env::var("TLS_KEY_PATH")) {
+            (Ok(cert_path), Ok(key_path)) => Some(TlsPaths {
+                cert_path,
+                key_path,
+            }),
+            (Err(_), Err(_)) => None,
+            _ => panic!("TLS_CERT_PATH and TLS_KEY_PATH must be set together"),
+        };
+
+        // Normalized to a leading slash and no trailing one, whichever way it's written
+        let path_prefix = env::var("PATH_PREFIX")
+            .map(|prefix| prefix.trim_matches('/').to_string())
+            .ok()
+            .filter(|prefix| !prefix.is_empty())
+            .map(|prefix| format!("/{:}", prefix))
+            .unwrap_or_default();
+
+        Self {
+            spotify_client_id: env::var("SPOTIFY_CLIENT_ID")
+                .expect("Expected SPOTIFY_CLIENT_ID env var"),
+            spotify_client_secret: env::var("SPOTIFY_CLIENT_SECRET")
+                .expect("Expected SPOTIFY_CLIENT_SECRET env var"),
+            spotify_refresh_token: env::var("SPOTIFY_REFRESH_TOKEN")
+                .expect("Expected SPOTIFY_REFRESH_TOKEN env var"),
+            external_auth_token: env::var("EXTERNAL_AUTH_TOKEN")
+                .expect("Expected EXTERNAL_AUTH_TOKEN env var"),
+            port: env::var("PORT").unwrap_or("3001".to_string()),
+            top_songs_fetch_limit,
+            compress_cache: env::var("COMPRESS_CACHE").is_ok_and(|compress| compress == "true"),
+            max_pages,
+            read_only: env::var("READ_ONLY").is_ok_and(|read_only| read_only == "true"),
+            history_max,
+            tls,
+            fallback_image_url: env::var("FALLBACK_IMAGE_URL").ok(),
+            canvas_provider_url: env::var("CANVAS_PROVIDER_URL").ok(),
+            frontend_url: env::var("FRONTEND_URL").ok(),
+            synced_lyrics_provider_url: env::var("SYNCED_LYRICS_PROVIDER_URL").ok(),
+            token_refresh_retries,
+            device_wake_retries,
+            timezone,
+            cors_allowed_origins: env::var("CORS_ALLOWED_ORIGINS")
+                .map(|origins| {
+                    origins
+                        .split(',')
+                        .map(|origin| origin.trim().into())
+                        .collect()
+                })
+                .unwrap_or(
+                    DEFAULT_CORS_ALLOWED_ORIGINS
+                        .into_iter()
+                        .map(String::from)
+                        .collect(),
+                ),
+            cors_allow_credentials: env::var("CORS_ALLOW_CREDENTIALS")
+                .is_ok_and(|allow| allow == "true"),
+            max_items,
+            refresh_active_device: env::var("REFRESH_ACTIVE_DEVICE")
+                .is_ok_and(|refresh| refresh == "true"),
+            sse_keepalive: Duration::from_secs(sse_keepalive),
+            trust_proxy: env::var("TRUST_PROXY").is_ok_and(|trust| trust == "true"),
+            max_stream_clients,
+            max_concurrent_spotify_requests,
+            idle_backoff_max: Duration::from_secs(idle_backoff_max),
+            path_prefix,
+        }
+    }
+}
+
+#[cfg(test)]
+impl Config {
+    /// Defaults with placeholder credentials, for requests against mock servers
+    pub fn for_tests() -> Self {
+        for (key, value) in [
+            ("SPOTIFY_CLIENT_ID", "client-id"),
+            ("SPOTIFY_CLIENT_SECRET", "client-secret"),
+            ("SPOTIFY_REFRESH_TOKEN", "refresh-token"),
+            ("EXTERNAL_AUTH_TOKEN", "external-token"),
+        ] {
+            env::set_var(key, value);
+        }
+
+        Self::from_env()
+    }
+}
@@ -0,0 +1,72 @@
+//! The camelCase JSON of the current song and top songs is a contract with the
+//! frontend, documented by the reference responses. Checked by the tests so a
+//! renamed field or changed serde attribute can't silently break the frontend.
+
+use std::collections::BTreeSet;
+
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
+
+use crate::{
+    response::to_snake_case,
+    spotify::{CurrentSong, Item},
+};
+
+const CURRENT_SONG: &str = include_str!("../reference/spot/current-song.json");
+const TOP_SONGS: &str = include_str!("../reference/spot/top-songs.json");
+
+#[test]
+fn current_song_matches_the_reference() {
+    check_reference::<CurrentSong>("current-song.json", CURRENT_SONG);
+}
+
+#[test]
+fn top_songs_match_the_reference() {
+    check_reference::<Vec<Item>>("top-songs.json", TOP_SONGS);
+}
+
+/// Reads the reference back in like a Spotify response, Spotify uses the
+/// snake_case keys and computed fields are ignored, then serializes it again.
+/// Panics when the keys serialized differ from the reference.
+fn check_reference<T: DeserializeOwned + Serialize>(name: &str, reference: &str) {
+    let reference: Value = serde_json::from_str(reference)
+        .unwrap_or_else(|err| panic!("Reference {:} is invalid json: {:}", name, err));
+    let parsed: T = serde_json::from_value(to_snake_case(reference.clone()))
+        .unwrap_or_else(|err| panic!("Reference {:} doesn't parse: {:}", name, err));
+    let serialized = serde_json::to_value(parsed).unwrap();
+
+    let expected = key_paths(&reference);
+    let actual = key_paths(&serialized);
+    if expected != actual {
+        panic!(
+            "Serialized keys differ from reference {:}, missing {:?}, unexpected {:?}",
+            name,
+            expected.difference(&actual).collect::<Vec<_>>(),
+            actual.difference(&expected).collect::<Vec<_>>()
+        );
+    }
+}
+
+fn key_paths(value: &Value) -> BTreeSet<String> {
+    let mut paths = BTreeSet::new();
+    collect_key_paths(value, "", &mut paths);
+    paths
+}
+
+fn collect_key_paths(value: &Value, prefix: &str, paths: &mut BTreeSet<String>) {
+    match value {
+        Value::Object(object) => {
+            for (key, value) in object {
+                let path = format!("{:}.{:}", prefix, key);
+                collect_key_paths(value, &path, paths);
+                paths.insert(path);
+            }
+        }
+        Value::Array(values) => {
+            for value in values {
+                collect_key_paths(value, &format!("{:}[]", prefix), paths);
+            }
+        }
+        _ => {}
+    }
+}
@@ -0,0 +1,69 @@
+use std::{io, sync::OnceLock, time::Duration};
+
+use axum::body::Bytes;
+use futures_util::{Stream, StreamExt};
+
+/// Canvas and lyrics providers, album art and previews are extras, a third
+/// party hanging shouldn't hold up the state lock for longer than this
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+static CLIENT: OnceLock<reqwest::Client> = OnceLock::new();
+
+/// Client for requests outside Spotify's api, giving up after `TIMEOUT`
+pub fn client() -> &'static reqwest::Client {
+    CLIENT.get_or_init(|| {
+        reqwest::Client::builder()
+            .timeout(TIMEOUT)
+            .build()
+            .unwrap_or_default()
+    })
+}
+
+/// Whether the response says it's longer than `max_bytes`, ones that don't
+/// say are cut off by `capped_stream` instead
+pub fn too_large(response: &reqwest::Response, max_bytes: usize) -> bool {
+    response
+        .content_length()
+        .is_some_and(|length| length > max_bytes as u64)
+}
+
+/// Reads the body in chunks so a broken response can't grow it without bound,
+/// `None` once it's larger than `max_bytes`
+pub async fn read_capped(
+    mut response: reqwest::Response,
+    max_bytes: usize,
+) -> Result<Option<Vec<u8>>, reqwest::Error> {
+    if too_large(&response, max_bytes) {
+        return Ok(None);
+    }
+
+    let mut bytes = Vec::new();
+    while let Some(chunk) = response.chunk().await? {
+        bytes.extend_from_slice(&chunk);
+        if bytes.len() > max_bytes {
+            return Ok(None);
+        }
+    }
+
+    Ok(Some(bytes))
+}
+
+/// Body of the response, failing once more than `max_bytes` were read
+pub fn capped_stream(
+    response: reqwest::Response,
+    max_bytes: usize,
+) -> impl Stream<Item = Result<Bytes, io::Error>> {
+    let mut read = 0;
+    response.bytes_stream().map(move |chunk| {
+        let chunk = chunk.map_err(io::Error::other)?;
+        read += chunk.len();
+        if read > max_bytes {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Response larger than allowed",
+            ));
+        }
+
+        Ok(chunk)
+    })
+}
@@ -0,0 +1,107 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex},
+};
+
+use chrono::Timelike;
+use chrono_tz::Tz;
+use serde::Serialize;
+
+use crate::spotify::{serialize_rfc3339, Item};
+
+/// Tracks seen playing, oldest first. Shared between the client recording
+/// plays and the metrics reading the count, entries past `max` are evicted
+/// oldest first.
+#[derive(Clone)]
+pub struct History {
+    log: Arc<Mutex<Log>>,
+}
+
+struct Log {
+    entries: VecDeque<HistoryEntry>,
+    max: usize,
+    // Progress of the latest entry when it was last seen, a lower progress means it was replayed
+    last_progress_ms: i64,
+}
+
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct HistoryEntry {
+    pub played_at: i64,
+    #[serde(serialize_with = "serialize_rfc3339")]
+    pub played_at_iso: i64,
+    pub item: Item,
+    /// Plays of the track across the whole history, replays back to back included
+    pub play_count: usize,
+}
+
+impl History {
+    pub fn new(max: usize) -> Self {
+        Self {
+            log: Arc::new(Mutex::new(Log {
+                entries: VecDeque::with_capacity(max),
+                max,
+                last_progress_ms: 0,
+            })),
+        }
+    }
+
+    /// Records `item` as played unless it's the latest entry still playing
+    pub fn record(&self, item: &Item, progress_ms: i64, now: i64) {
+        let mut log = self.log.lock().unwrap();
+        let still_playing = log
+            .entries
+            .back()
+            .is_some_and(|latest| latest.item.key() == item.key())
+            && progress_ms >= log.last_progress_ms;
+        log.last_progress_ms = progress_ms;
+        if still_playing || log.max == 0 {
+            return;
+        }
+
+        if log.entries.len() == log.max {
+            log.entries.pop_front();
+        }
+
+        let played_at = now - progress_ms;
+        log.entries.push_back(HistoryEntry {
+            played_at,
+            played_at_iso: played_at,
+            item: item.clone(),
+            play_count: 0,
+        });
+    }
+
+    /// Most recently played first
+    pub fn entries(&self) -> Vec<HistoryEntry> {
+        let log = self.log.lock().unwrap();
+        let mut play_counts: HashMap<&str, usize> = HashMap::new();
+        for entry in &log.entries {
+            *play_counts.entry(entry.item.key()).or_default() += 1;
+        }
+
+        log.entries
+            .iter()
+            .rev()
+            .map(|entry| HistoryEntry {
+                play_count: play_counts[entry.item.key()],
+                ..entry.clone()
+            })
+            .collect()
+    }
+
+    /// Plays per hour of the day in `timezone`, midnight first
+    pub fn hourly(&self, timezone: Tz) -> [usize; 24] {
+        let mut hours = [0; 24];
+        for entry in &self.log.lock().unwrap().entries {
+            if let Some(played_at) = chrono::DateTime::from_timestamp_millis(entry.played_at) {
+                hours[played_at.with_timezone(&timezone).hour() as usize] += 1;
+            }
+        }
+        hours
+    }
+
+    pub fn count(&self) -> usize {
+        self.log.lock().unwrap().entries.len()
+    }
+}
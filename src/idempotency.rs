@@ -0,0 +1,249 @@
+//! Player writes retried with the same `Idempotency-Key` get the response of
+//! the first attempt instead of running again, so a flaky connection retrying
+//! a skip doesn't skip twice.
+
+use std::collections::HashMap;
+
+use axum::{
+    body::{self, Bytes},
+    http::{header, HeaderMap, HeaderValue, Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Extension,
+};
+use http_body::Body as _;
+use tracing::{error, info};
+
+use crate::{is_authorized, SharedState};
+
+/// How long the response to a key is replayed
+pub const WINDOW: i64 = 60 * 1000;
+
+/// Responses by method, uri and key, dropped once their window passed
+#[derive(Default)]
+pub struct IdempotencyKeys {
+    entries: HashMap<String, Entry>,
+}
+
+enum Entry {
+    /// Marked under the same lock as the lookup, so a retry racing the first
+    /// attempt can't run the write a second time
+    InFlight {
+        expires_at: i64,
+    },
+    Done(StoredResponse),
+}
+
+impl Entry {
+    fn expires_at(&self) -> i64 {
+        match self {
+            Entry::InFlight { expires_at } => *expires_at,
+            Entry::Done(stored) => stored.expires_at,
+        }
+    }
+}
+
+#[derive(Clone)]
+struct StoredResponse {
+    status: StatusCode,
+    content_type: Option<HeaderValue>,
+    body: Bytes,
+    expires_at: i64,
+}
+
+enum Lookup {
+    Replay(StoredResponse),
+    InFlight,
+    Started,
+}
+
+impl IdempotencyKeys {
+    /// Response stored for the key, or marks it in flight when there's none
+    fn start(&mut self, key: &str, now: i64) -> Lookup {
+        self.entries.retain(|_, entry| entry.expires_at() > now);
+        match self.entries.get(key) {
+            Some(Entry::Done(stored)) => Lookup::Replay(stored.clone()),
+            Some(Entry::InFlight { .. }) => Lookup::InFlight,
+            None => {
+                self.entries.insert(
+                    key.to_string(),
+                    Entry::InFlight {
+                        expires_at: now + WINDOW,
+                    },
+                );
+                Lookup::Started
+            }
+        }
+    }
+
+    fn finish(&mut self, key: String, response: StoredResponse) {
+        self.entries.insert(key, Entry::Done(response));
+    }
+
+    /// Forgets a key whose request failed or never finished, so it can be retried
+    fn abandon(&mut self, key: &str) {
+        self.entries.remove(key);
+    }
+}
+
+/// Abandons the key of a request dropped before it finished, like when the
+/// client disconnected, unless it was disarmed
+struct InFlightGuard {
+    state: SharedState,
+    key: Option<String>,
+}
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        if let Some(key) = self.key.take() {
+            let state = self.state.clone();
+            tokio::spawn(async move { state.lock().await.idempotency_keys.abandon(&key) });
+        }
+    }
+}
+
+/// Replays the stored response of an authorized request with a known key,
+/// marked by an `idempotent-replayed` header, or runs and stores it. A retry
+/// arriving while the first attempt still runs gets a `409`. Server errors
+/// aren't stored, retrying those runs the request again.
+pub async fn replay<B>(
+    Extension(state): Extension<SharedState>,
+    headers: HeaderMap,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let Some(Ok(idempotency_key)) = headers.get("Idempotency-Key").map(|key| key.to_str()) else {
+        return next.run(request).await;
+    };
+    let key = format!(
+        "{:} {:} {:}",
+        request.method(),
+        request.uri(),
+        idempotency_key
+    );
+
+    {
+        let state = &mut state.lock().await;
+        // Unauthorized requests are left for the handler to reject
+        if !is_authorized(&headers, &state.token) {
+            return next.run(request).await;
+        }
+
+        let now = chrono::Utc::now().timestamp_millis();
+        match state.idempotency_keys.start(&key, now) {
+            Lookup::Replay(stored) => {
+                info!(idempotency_key, "Replaying response");
+                let mut response = stored.into_response();
+                response
+                    .headers_mut()
+                    .insert("idempotent-replayed", HeaderValue::from_static("true"));
+                return response;
+            }
+            Lookup::InFlight => {
+                info!(idempotency_key, "Request with the key still in flight");
+                return Response::builder()
+                    .status(StatusCode::CONFLICT)
+                    .body(body::Empty::new())
+                    .unwrap()
+                    .into_response();
+            }
+            Lookup::Started => {}
+        }
+    }
+
+    let mut guard = InFlightGuard {
+        state: state.clone(),
+        key: Some(key),
+    };
+    let (parts, mut response_body) = next.run(request).await.into_parts();
+    let mut bytes = Vec::new();
+    while let Some(chunk) = response_body.data().await {
+        match chunk {
+            Ok(chunk) => bytes.extend_from_slice(&chunk),
+            Err(err) => {
+                error!(%err, "Could not read response to store");
+                return Response::builder()
+                    .status(StatusCode::INTERNAL_SERVER_ERROR)
+                    .body(body::Empty::new())
+                    .unwrap()
+                    .into_response();
+            }
+        }
+    }
+
+    let stored = StoredResponse {
+        status: parts.status,
+        content_type: parts.headers.get(header::CONTENT_TYPE).cloned(),
+        body: Bytes::from(bytes),
+        expires_at: chrono::Utc::now().timestamp_millis() + WINDOW,
+    };
+    let key = guard.key.take().unwrap();
+    let idempotency_keys = &mut state.lock().await.idempotency_keys;
+    if stored.status.is_server_error() {
+        idempotency_keys.abandon(&key);
+    } else {
+        idempotency_keys.finish(key, stored.clone());
+    }
+    stored.into_response()
+}
+
+impl IntoResponse for StoredResponse {
+    fn into_response(self) -> Response {
+        let mut response = Response::builder().status(self.status);
+        if let Some(content_type) = self.content_type {
+            response = response.header(header::CONTENT_TYPE, content_type);
+        }
+        response
+            .body(body::Full::new(self.body))
+            .unwrap()
+            .into_response()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stored(status: StatusCode, now: i64) -> StoredResponse {
+        StoredResponse {
+            status,
+            content_type: None,
+            body: Bytes::new(),
+            expires_at: now + WINDOW,
+        }
+    }
+
+    #[test]
+    fn retries_wait_for_the_first_attempt() {
+        let mut keys = IdempotencyKeys::default();
+
+        assert!(matches!(keys.start("key", 0), Lookup::Started));
+        assert!(matches!(keys.start("key", 1), Lookup::InFlight));
+
+        keys.finish("key".into(), stored(StatusCode::NO_CONTENT, 2));
+        assert!(matches!(
+            keys.start("key", 3),
+            Lookup::Replay(StoredResponse {
+                status: StatusCode::NO_CONTENT,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn abandoned_keys_run_again() {
+        let mut keys = IdempotencyKeys::default();
+
+        assert!(matches!(keys.start("key", 0), Lookup::Started));
+        keys.abandon("key");
+        assert!(matches!(keys.start("key", 1), Lookup::Started));
+    }
+
+    #[test]
+    fn keys_expire_after_the_window() {
+        let mut keys = IdempotencyKeys::default();
+
+        assert!(matches!(keys.start("key", 0), Lookup::Started));
+        assert!(matches!(keys.start("key", WINDOW), Lookup::Started));
+    }
+}
@@ -1,24 +1,68 @@
+mod backoff;
+mod cache;
+mod client_ip;
+mod config;
+#[cfg(test)]
+mod contract;
+mod download;
+mod history;
+mod idempotency;
+mod metrics;
+mod palette;
+mod queries;
+mod response;
+mod scheduler;
 mod spotify;
+mod stream;
+mod tls;
+mod ws;
 
-use std::{env, sync::Arc};
+use std::{
+    cell::{Cell, RefCell},
+    collections::HashMap,
+    io::ErrorKind,
+    net::{SocketAddr, TcpListener},
+    sync::Arc,
+};
 
 use axum::{
-    body,
-    extract::{Path, Query},
-    http::{request::Parts, HeaderMap, HeaderValue, StatusCode},
-    response::{IntoResponse, Response},
-    routing::{get, post},
+    body::{self, Body, StreamBody},
+    extract::{MatchedPath, Path, Query},
+    http::{
+        header, request::Parts, HeaderMap, HeaderName, HeaderValue, Method, Request, StatusCode,
+    },
+    middleware::{self, Next},
+    response::{Html, IntoResponse, Response},
+    routing::{delete, get, post, put},
     Extension, Json, Router,
 };
-use serde::Deserialize;
-use spotify::{MediaState, Spot};
+use backoff::IdleBackoff;
+use config::Config;
+use history::History;
+use http_body::Limited;
+use idempotency::IdempotencyKeys;
+use metrics::{Metrics, MetricsSnapshot};
+use queries::{PlayOnDeviceQuery, PlayQuery, RecentlyPlayedQuery, SeekQuery};
+use response::{json, project, to_snake_case, Case, ResponseOptions};
+use scheduler::{Scheduler, SPOTIFY_CALLS};
+use serde::{Deserialize, Serialize};
+use spotify::{
+    is_spotify_id, serialize_rfc3339, AdditionalType, CacheSnapshot, CacheStatus, ImageSize,
+    MediaState, RepeatMode, Spot, SpotError, TimeRange, SPOTIFY_ERROR, TUNABLE_ATTRIBUTES,
+};
+use stream::{SseKeepAlive, StreamClients};
 use tokio::sync::Mutex;
-use tower_http::cors::{AllowOrigin, CorsLayer};
-use tracing::{info, instrument, level_filters::LevelFilter};
+use tower_http::{
+    cors::{AllowOrigin, CorsLayer},
+    limit::RequestBodyLimitLayer,
+};
+use tracing::{error, info, instrument, level_filters::LevelFilter};
 use tracing_subscriber::{fmt, prelude::*, EnvFilter, Registry};
 
 use crate::spotify::Item;
 
+const WRITE_BODY_LIMIT: usize = 16 * 1024;
+
 #[tokio::main]
 #[instrument]
 async fn main() {
@@ -33,11 +77,11 @@ async fn main() {
 
         let registry = Registry::default().with(env_filter).with(fmt::layer());
 
-        if let Ok(_) = std::env::var("AXIOM_TOKEN") {
+        if std::env::var("AXIOM_TOKEN").is_ok() {
             let axiom_layer = tracing_axiom::builder()
                 .with_service_name("spot")
                 .with_tags(&[(
-                    &"deployment_id",
+                    "deployment_id",
                     &std::env::var("RAILWAY_DEPLOYMENT_ID")
                         .map(|s| {
                             s + "-"
@@ -47,7 +91,7 @@ async fn main() {
                         })
                         .unwrap_or("unknown_deployment".into()),
                 )])
-                .with_tags(&[(&"service.name", "spot".into())])
+                .with_tags(&[("service.name", "spot")])
                 .layer()
                 .expect("Axiom layer failed to initialize");
 
@@ -61,64 +105,463 @@ async fn main() {
         }
     };
 
+    let config = Config::from_env();
+    let scheduler = Scheduler::new(config.max_concurrent_spotify_requests);
+    let history = History::new(config.history_max);
+    let stream_clients = StreamClients::new(config.max_stream_clients);
+    let idle_backoff = IdleBackoff::new(config.idle_backoff_max);
+    let metrics = Arc::new(Metrics::new(
+        scheduler.clone(),
+        history.clone(),
+        stream_clients.clone(),
+        idle_backoff.clone(),
+    ));
     let state = Arc::new(Mutex::new(State {
-        spot: Spot::new(
-            env::var("SPOTIFY_CLIENT_ID").expect("Expected SPOTIFY_CLIENT_ID env var"),
-            env::var("SPOTIFY_CLIENT_SECRET").expect("Expected SPOTIFY_CLIENT_SECRET env var"),
-            env::var("SPOTIFY_REFRESH_TOKEN").expect("Expected SPOTIFY_REFRESH_TOKEN env var"),
-        ),
-        token: env::var("EXTERNAL_AUTH_TOKEN").expect("Expected EXTERNAL_AUTH_TOKEN env var"),
+        spot: Spot::new(&config, scheduler, history, idle_backoff),
+        token: config.external_auth_token.clone(),
+        read_only: config.read_only,
+        idempotency_keys: IdempotencyKeys::default(),
+        last_device_id: None,
     }));
 
-    let state_two = state.clone();
-    let app = Router::new()
+    let mut routes = Router::new()
         .route("/", get(get_current_song))
         .route("/top-songs", get(get_top_songs))
-        .route("/player/:player_state", post(update_player_state))
-        .layer(CorsLayer::new().allow_origin(AllowOrigin::predicate(
-            |origin: &HeaderValue, _request_parts: &Parts| {
-                if let Ok(host) = origin.to_str() {
-                    return [
-                        "https://finndore.dev",
-                        "finnnn.vercel.app",
-                        "http://localhost:3000",
-                    ]
-                    .into_iter()
-                    .any(|allowed_origin| host.ends_with(allowed_origin));
+        .route("/top-songs/combined", get(get_combined_top_songs))
+        .route("/top-songs/compare", get(get_top_songs_comparison))
+        .route("/current/art", get(get_current_art))
+        .route("/current/palette", get(get_current_palette))
+        .route("/current/ends-in", get(get_current_ends_in))
+        .route("/current/in-playlists", get(get_playlists_with_current))
+        .route("/og", get(get_open_graph))
+        .route("/next-up", get(get_next_up))
+        .route("/canvas", get(get_canvas))
+        .route("/lyrics/synced", get(get_synced_lyrics))
+        .route("/preview/:track_id", get(get_preview))
+        .route("/track/:track_id/analysis", get(get_audio_analysis))
+        .route("/history", get(get_history))
+        .route("/recently-played", get(get_recently_played))
+        .route("/stats/hourly", get(get_hourly_stats))
+        .route("/stats/taste", get(get_taste))
+        .route("/ws", get(ws::handle))
+        .route("/stream", get(stream::handle))
+        .route("/artists", get(get_artists))
+        .route("/following", get(get_followed_artists))
+        .route("/saved-albums", get(get_saved_albums))
+        .route("/saved-shows", get(get_saved_shows))
+        .route("/liked", get(get_liked_tracks))
+        .route("/liked/contains", get(get_liked_ids))
+        .route("/playlists/search", get(search_playlists))
+        .route("/genre-seeds", get(get_genre_seeds))
+        .route("/recommendations", get(get_recommendations))
+        .route("/new-releases", get(get_new_releases))
+        .route("/featured-playlists", get(get_featured_playlists))
+        .route("/metrics", get(get_metrics))
+        .route("/devices", get(get_devices))
+        .route("/player", get(get_playback_state))
+        .route("/auth/scopes", get(get_scopes))
+        .route("/cache/status", get(get_cache_status))
+        .route("/admin/status", get(get_admin_status))
+        .route("/cache/clear", post(clear_caches))
+        .route("/cache/export", get(export_caches));
+
+    // Read only deployments don't expose anything changing the connected account
+    if config.read_only {
+        info!("Running in read only mode");
+    } else {
+        info!("Running in read write mode");
+        let write_routes = Router::<(), Limited<Body>>::new()
+            .route("/player/:player_state", post(update_player_state))
+            .route("/player/play", post(play))
+            .route("/player/seek", post(seek))
+            .route("/player/play-on", post(play_on_device))
+            .route("/player/play-liked", post(play_liked))
+            .route("/player/repeat-cycle", post(cycle_repeat))
+            .route("/player/add-recommendations", post(add_recommendations))
+            .route(
+                "/playlist/:playlist_id/tracks",
+                delete(remove_from_playlist),
+            )
+            .route("/playlist/:playlist_id/reorder", put(reorder_playlist))
+            .layer(middleware::from_fn(idempotency::replay))
+            // Control payloads are tiny, anything bigger is rejected with a 413
+            .layer(RequestBodyLimitLayer::new(WRITE_BODY_LIMIT));
+        // Replaces the cached token and songs, but snapshots are far larger
+        // than the control payloads the write body limit is for
+        routes = routes
+            .merge(write_routes)
+            .route("/cache/import", post(import_caches));
+    }
+
+    let state_two = state.clone();
+    let max_items = config.max_items;
+    let trust_proxy = config.trust_proxy;
+    let app = routes
+        .layer(middleware::from_fn(explain_missing_scope))
+        .layer(cors_layer(&config))
+        .route("/version", get(get_version))
+        .route("/health", get(get_health))
+        .route("/health/spotify", get(get_spotify_health))
+        .route("/ready", get(get_ready))
+        .layer(middleware::from_fn(move |request, next| {
+            enforce_max_items(max_items, request, next)
+        }))
+        .layer(middleware::from_fn(expose_spotify_error))
+        .layer(middleware::from_fn(count_spotify_calls))
+        .layer(middleware::from_fn(move |request, next| {
+            client_ip::attach(trust_proxy, request, next)
+        }))
+        .layer(Extension(state))
+        .layer(Extension(state_two))
+        .layer(Extension(metrics))
+        .layer(Extension(SseKeepAlive(config.sse_keepalive)))
+        .layer(Extension(FrontendUrl(config.frontend_url.clone())))
+        .layer(Extension(stream_clients));
+    // Everything, the health checks included, moves under the prefix so a
+    // gateway can forward the whole path without rewriting it
+    let app = if config.path_prefix.is_empty() {
+        app
+    } else {
+        info!(
+            path_prefix = config.path_prefix,
+            "Nesting routes under prefix"
+        );
+        Router::new().nest(&config.path_prefix, app)
+    };
+
+    let host = format!("0.0.0.0:{:}", config.port);
+    let listener = bind(&host, &config.port);
+    match config.tls {
+        Some(paths) => {
+            let tls_config = tls::load(&paths).await;
+            tls::watch(tls_config.clone(), paths);
+            info!("Running server on {:} with TLS", host);
+
+            axum_server::from_tcp_rustls(listener, tls_config)
+                .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+                .await
+                .unwrap();
+        }
+        None => {
+            info!("Running server on {:}", host);
+
+            axum::Server::from_tcp(listener)
+                .unwrap()
+                .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+                .await
+                .unwrap();
+        }
+    }
+}
+
+/// Exits with a readable message rather than panicking when the port can't be bound
+fn bind(host: &str, port: &str) -> TcpListener {
+    let listener = TcpListener::bind(host).and_then(|listener| {
+        listener.set_nonblocking(true)?;
+        Ok(listener)
+    });
+
+    match listener {
+        Ok(listener) => listener,
+        Err(err) if err.kind() == ErrorKind::AddrInUse => {
+            error!("Port {:} already in use, set PORT to a free port", port);
+            std::process::exit(1);
+        }
+        Err(err) => {
+            error!(%err, "Could not bind {:}", host);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Allowed origins with a scheme have to match exactly, ones without match
+/// that host and its subdomains on any scheme. `example.com` allows
+/// `https://app.example.com` but not `https://notexample.com`.
+fn origin_allowed(origin: &str, allowed_origins: &[String]) -> bool {
+    let host = origin.split_once("://").map_or(origin, |(_, host)| host);
+    allowed_origins.iter().any(|allowed_origin| {
+        if allowed_origin.contains("://") {
+            return origin == allowed_origin;
+        }
+
+        host == allowed_origin
+            || host
+                .strip_suffix(allowed_origin.as_str())
+                .is_some_and(|subdomain| subdomain.ends_with('.'))
+    })
+}
+
+/// The methods and headers are spelled out so preflights of the authenticated
+/// write endpoints pass.
+fn cors_layer(config: &Config) -> CorsLayer {
+    let allowed_origins = config.cors_allowed_origins.clone();
+    CorsLayer::new()
+        .allow_origin(AllowOrigin::predicate(
+            move |origin: &HeaderValue, _request_parts: &Parts| {
+                if let Ok(origin) = origin.to_str() {
+                    return origin_allowed(origin, &allowed_origins);
                 }
                 info!(?origin, "Cors layer failed to parse origin header");
                 false
             },
-        )))
-        .layer(Extension(state))
-        .layer(Extension(state_two));
-
-    let port = std::env::var("PORT").unwrap_or("3001".to_string());
-    let host = format!("0.0.0.0:{:}", port);
-    info!("Running server on {:}", host);
-
-    axum::Server::bind(&host.to_string().parse().unwrap())
-        .serve(app.into_make_service())
-        .await
-        .unwrap();
+        ))
+        .allow_methods([Method::GET, Method::POST, Method::PUT, Method::DELETE])
+        .allow_headers([
+            header::AUTHORIZATION,
+            header::CONTENT_TYPE,
+            header::IF_NONE_MATCH,
+            HeaderName::from_static("idempotency-key"),
+        ])
+        .allow_credentials(config.cors_allow_credentials)
 }
 
 struct State {
     spot: Spot,
     token: String,
+    read_only: bool,
+    idempotency_keys: IdempotencyKeys,
+    // Device of the playback state last returned
+    last_device_id: Option<String>,
 }
 
+/// The lock is held for the whole of a `Spot` call, including the request to
+/// Spotify. That makes every cached read single flight: concurrent requests
+/// missing the cache queue up behind the first one and are then served from
+/// what it cached. Only the downloads of art, previews and palettes outside
+/// Spotify's api happen after the lock is released, and those aren't
+/// coalesced. Splitting the lock for `Spot` calls means coalescing those
+/// requests some other way.
 type SharedState = Arc<Mutex<State>>;
 
+/// Accepts the bare token as well as `Bearer <token>`, which HTTP clients default to
+fn is_authorized(headers: &HeaderMap, token: &str) -> bool {
+    let Some(Ok(incoming_token)) = headers.get("Authorization").map(|value| value.to_str()) else {
+        return false;
+    };
+    let incoming_token = match incoming_token.split_once(' ') {
+        Some((scheme, bearer_token)) if scheme.eq_ignore_ascii_case("bearer") => {
+            bearer_token.trim()
+        }
+        _ => incoming_token,
+    };
+    incoming_token == token
+}
+
+#[derive(Deserialize, Debug)]
+struct DebugQuery {
+    #[serde(default)]
+    debug: bool,
+}
+
+/// Adds an `x-spotify-calls` header with the number of Spotify requests made
+/// to serve an authorized `?debug=true` request, `0` meaning it came from cache
+async fn count_spotify_calls<B>(
+    Extension(state): Extension<SharedState>,
+    query: Option<Query<DebugQuery>>,
+    headers: HeaderMap,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    if !query.is_some_and(|Query(query)| query.debug)
+        || !is_authorized(&headers, &state.lock().await.token)
+    {
+        return next.run(request).await;
+    }
+
+    let (calls, mut response) = SPOTIFY_CALLS
+        .scope(Cell::new(0), async {
+            let response = next.run(request).await;
+            (SPOTIFY_CALLS.with(Cell::get), response)
+        })
+        .await;
+    response
+        .headers_mut()
+        .insert("x-spotify-calls", HeaderValue::from(calls));
+    response
+}
+
+#[derive(Serialize)]
+struct SpotifyErrorBody {
+    error: &'static str,
+    status: u16,
+    message: String,
+}
+
+/// Answers an authorized `?debug=true` request failing with a 5xx with the
+/// error Spotify gave, `{"error": "spotify_error", "status", "message"}`.
+/// Public requests only get the status, the message can leak details.
+async fn expose_spotify_error<B>(
+    Extension(state): Extension<SharedState>,
+    query: Option<Query<DebugQuery>>,
+    headers: HeaderMap,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    if !query.is_some_and(|Query(query)| query.debug)
+        || !is_authorized(&headers, &state.lock().await.token)
+    {
+        return next.run(request).await;
+    }
+
+    let (spotify_error, response) = SPOTIFY_ERROR
+        .scope(RefCell::new(None), async {
+            let response = next.run(request).await;
+            (SPOTIFY_ERROR.with(RefCell::take), response)
+        })
+        .await;
+    let Some(spotify_error) = spotify_error.filter(|_| response.status().is_server_error()) else {
+        return response;
+    };
+
+    // Keeps the headers of the failed response, like the CORS ones
+    let (mut parts, _) = response.into_parts();
+    let body = SpotifyErrorBody {
+        error: "spotify_error",
+        status: spotify_error.status,
+        message: spotify_error.message,
+    };
+    parts.headers.remove(header::CONTENT_LENGTH);
+    parts.headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("application/json"),
+    );
+    Response::from_parts(
+        parts,
+        body::boxed(body::Full::from(serde_json::to_vec(&body).unwrap())),
+    )
+}
+
+#[derive(Serialize)]
+struct MissingScope {
+    error: &'static str,
+    scope: &'static str,
+}
+
+/// Replaces the failure of a route whose scope the refresh token wasn't
+/// granted with a `403` naming the scope, so it's clear re-authenticating fixes it
+async fn explain_missing_scope<B>(
+    Extension(state): Extension<SharedState>,
+    route: Option<MatchedPath>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let response = next.run(request).await;
+    if response.status() != StatusCode::FORBIDDEN && !response.status().is_server_error() {
+        return response;
+    }
+
+    let Some(route) = route else {
+        return response;
+    };
+    match state.lock().await.spot.missing_scope(route.as_str()) {
+        Some(scope) => (
+            StatusCode::FORBIDDEN,
+            Json(MissingScope {
+                error: "missing_scope",
+                scope,
+            }),
+        )
+            .into_response(),
+        None => response,
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct LimitQuery {
+    limit: Option<usize>,
+}
+
+/// Rejects any `limit` above `MAX_ITEMS` with a `400`, whichever list it's for
+async fn enforce_max_items<B>(max_items: usize, request: Request<B>, next: Next<B>) -> Response {
+    let limit = Query::<LimitQuery>::try_from_uri(request.uri())
+        .ok()
+        .and_then(|Query(query)| query.limit);
+    if limit.is_some_and(|limit| limit > max_items) {
+        return Response::builder()
+            .status(StatusCode::BAD_REQUEST)
+            .body(body::Empty::new())
+            .unwrap()
+            .into_response();
+    }
+
+    next.run(request).await
+}
+
+fn spot_error_status(error: &SpotError) -> StatusCode {
+    match error {
+        SpotError::NotFound => StatusCode::NOT_FOUND,
+        SpotError::Forbidden => StatusCode::FORBIDDEN,
+        SpotError::RefreshTokenRevoked => StatusCode::SERVICE_UNAVAILABLE,
+        SpotError::Unknown => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct DryRunQuery {
+    #[serde(default)]
+    dry_run: bool,
+}
+
+/// What a player write would have done, returned instead of doing it for `?dry_run=true`
+#[derive(Serialize, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+struct PlannedAction {
+    action: String,
+    device_name: Option<String>,
+    uri: Option<String>,
+    position_ms: Option<i64>,
+    shuffle: Option<bool>,
+    repeat_state: Option<RepeatMode>,
+    /// Device the action targets, the active one without
+    #[serde(skip)]
+    device_id: Option<String>,
+}
+
+/// Answers a dry run with the planned action on the targeted or active device,
+/// `404` without one as Spotify would for the real request
+async fn dry_run(
+    spot: &mut Spot,
+    options: &ResponseOptions,
+    mut action: PlannedAction,
+) -> Response {
+    let devices = match spot.get_devices().await {
+        Ok(devices) => devices,
+        Err(_) => {
+            return Response::builder()
+                .status(StatusCode::INTERNAL_SERVER_ERROR)
+                .body(body::Empty::new())
+                .unwrap()
+                .into_response()
+        }
+    };
+
+    let target = devices.into_iter().find(|device| match &action.device_id {
+        Some(device_id) => device.id.as_ref() == Some(device_id),
+        None => device.is_active,
+    });
+    match target {
+        Some(device) => {
+            action.device_name = Some(device.name);
+            json(options, &action)
+        }
+        None => Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(body::Empty::new())
+            .unwrap()
+            .into_response(),
+    }
+}
+
 #[instrument(skip(state, headers))]
 async fn update_player_state(
     Path(new_player_state): Path<MediaState>,
+    Query(options): Query<ResponseOptions>,
+    Query(dry_run_query): Query<DryRunQuery>,
     Extension(state): Extension<SharedState>,
     headers: HeaderMap,
 ) -> Response {
     let state = &mut state.lock().await;
-    let incoming_token = headers.get("Authorization");
-    if incoming_token.is_none() || incoming_token.unwrap() != &state.token {
+    if !is_authorized(&headers, &state.token) {
         return Response::builder()
             .status(StatusCode::UNAUTHORIZED)
             .body(body::Empty::new())
@@ -126,6 +569,14 @@ async fn update_player_state(
             .into_response();
     }
 
+    if dry_run_query.dry_run {
+        let action = PlannedAction {
+            action: new_player_state.to_string(),
+            ..Default::default()
+        };
+        return dry_run(&mut state.spot, &options, action).await;
+    }
+
     info!(%new_player_state, "Updating player state");
     match state.spot.update_player_state(new_player_state).await {
         Ok(_) => Response::builder()
@@ -141,12 +592,123 @@ async fn update_player_state(
     }
 }
 
-#[instrument(skip(state))]
-async fn get_current_song(Extension(state): Extension<SharedState>) -> Response {
-    let spot = &mut state.lock().await.spot;
-    info!("Getting current song ",);
-    match spot.get_current_song().await {
-        Ok(song) => Json(song).into_response(),
+/// `404` unless the connected account has a device with the id, for writes
+/// targeting a device that isn't active
+async fn unknown_device(spot: &mut Spot, device_id: &str) -> Option<Response> {
+    let status = match spot.get_devices().await {
+        Ok(devices)
+            if devices
+                .iter()
+                .any(|device| device.id.as_deref() == Some(device_id)) =>
+        {
+            return None
+        }
+        Ok(_) => StatusCode::NOT_FOUND,
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+
+    Some(
+        Response::builder()
+            .status(status)
+            .body(body::Empty::new())
+            .unwrap()
+            .into_response(),
+    )
+}
+
+/// Resumes playback like `/player/play` always did, with a `uri`, `position_ms`,
+/// `shuffle` or `device_id` that's started from the position and the new state
+/// returned
+#[instrument(skip(state, headers))]
+async fn play(
+    Query(query): Query<PlayQuery>,
+    Query(options): Query<ResponseOptions>,
+    Query(dry_run_query): Query<DryRunQuery>,
+    Extension(state): Extension<SharedState>,
+    headers: HeaderMap,
+) -> Response {
+    if query.uri.is_none()
+        && query.position_ms.is_none()
+        && query.shuffle.is_none()
+        && query.device_id.is_none()
+    {
+        return update_player_state(
+            Path(MediaState::Play),
+            Query(options),
+            Query(dry_run_query),
+            Extension(state),
+            headers,
+        )
+        .await;
+    }
+
+    let state = &mut state.lock().await;
+    if !is_authorized(&headers, &state.token) {
+        return Response::builder()
+            .status(StatusCode::UNAUTHORIZED)
+            .body(body::Empty::new())
+            .unwrap()
+            .into_response();
+    }
+
+    // A new uri starts from its beginning anyway, resuming keeps the position
+    let position_ms = query.position_ms;
+    if position_ms.is_some_and(|position_ms| position_ms < 0) {
+        return Response::builder()
+            .status(StatusCode::BAD_REQUEST)
+            .body(body::Empty::new())
+            .unwrap()
+            .into_response();
+    }
+
+    if dry_run_query.dry_run {
+        let action = PlannedAction {
+            action: "play".into(),
+            uri: query.uri,
+            position_ms,
+            shuffle: query.shuffle,
+            device_id: query.device_id,
+            ..Default::default()
+        };
+        return dry_run(&mut state.spot, &options, action).await;
+    }
+
+    if let Some(device_id) = &query.device_id {
+        if let Some(response) = unknown_device(&mut state.spot, device_id).await {
+            return response;
+        }
+    }
+
+    info!(
+        uri = query.uri,
+        position_ms,
+        shuffle = query.shuffle,
+        device_id = query.device_id,
+        "Playing from position"
+    );
+    if let Err(error) = state
+        .spot
+        .play_from(
+            query.uri.as_deref(),
+            position_ms,
+            query.shuffle,
+            query.device_id.as_deref(),
+        )
+        .await
+    {
+        return Response::builder()
+            .status(spot_error_status(&error))
+            .body(body::Empty::new())
+            .unwrap()
+            .into_response();
+    }
+
+    match state
+        .spot
+        .get_current_song(&[AdditionalType::Track, AdditionalType::Episode])
+        .await
+    {
+        Ok(song) => json(&options, &song),
         Err(_) => Response::builder()
             .status(StatusCode::INTERNAL_SERVER_ERROR)
             .body(body::Empty::new())
@@ -155,50 +717,2012 @@ async fn get_current_song(Extension(state): Extension<SharedState>) -> Response
     }
 }
 
-#[derive(Deserialize)]
-struct TopSongsQuery {
-    limit: Option<usize>,
+#[instrument(skip(state, headers))]
+async fn seek(
+    Query(query): Query<SeekQuery>,
+    Query(options): Query<ResponseOptions>,
+    Query(dry_run_query): Query<DryRunQuery>,
+    Extension(state): Extension<SharedState>,
+    headers: HeaderMap,
+) -> Response {
+    let state = &mut state.lock().await;
+    if !is_authorized(&headers, &state.token) {
+        return Response::builder()
+            .status(StatusCode::UNAUTHORIZED)
+            .body(body::Empty::new())
+            .unwrap()
+            .into_response();
+    }
+
+    let target = match query.target() {
+        Some(target) => target,
+        None => {
+            return Response::builder()
+                .status(StatusCode::BAD_REQUEST)
+                .body(body::Empty::new())
+                .unwrap()
+                .into_response()
+        }
+    };
+
+    // Only the cached song knows the duration a percentage is relative to
+    let duration_ms = state
+        .spot
+        .current_song_cached_response
+        .as_ref()
+        .map(|current_song| current_song.duration_ms());
+    let position_ms = match target.position_ms(duration_ms) {
+        Some(position_ms) => position_ms,
+        None => {
+            return Response::builder()
+                .status(StatusCode::CONFLICT)
+                .body(body::Empty::new())
+                .unwrap()
+                .into_response()
+        }
+    };
+
+    if dry_run_query.dry_run {
+        let action = PlannedAction {
+            action: "seek".into(),
+            position_ms: Some(position_ms),
+            device_id: query.device_id,
+            ..Default::default()
+        };
+        return dry_run(&mut state.spot, &options, action).await;
+    }
+
+    if let Some(device_id) = &query.device_id {
+        if let Some(response) = unknown_device(&mut state.spot, device_id).await {
+            return response;
+        }
+    }
+
+    info!(position_ms, device_id = query.device_id, "Seeking");
+    match state
+        .spot
+        .seek(position_ms, query.device_id.as_deref())
+        .await
+    {
+        Ok(_) => Response::builder()
+            .status(StatusCode::OK)
+            .body(body::Empty::new())
+            .unwrap()
+            .into_response(),
+        Err(_) => Response::builder()
+            .status(StatusCode::INTERNAL_SERVER_ERROR)
+            .body(body::Empty::new())
+            .unwrap()
+            .into_response(),
+    }
 }
 
-#[instrument(skip(state, query))]
-async fn get_top_songs(
+#[instrument(skip(state, headers))]
+async fn get_devices(
+    Query(options): Query<ResponseOptions>,
     Extension(state): Extension<SharedState>,
-    query: Option<Query<TopSongsQuery>>,
+    headers: HeaderMap,
 ) -> Response {
-    let limit = query.map(|q| q.limit).flatten().unwrap_or(4);
-    let spot = &mut state.lock().await.spot;
-    info!("Getting top songs");
-    match spot.get_top_songs().await {
-        Ok(songs) => Json(songs.into_iter().take(limit).collect::<Vec<Item>>()).into_response(),
+    let state = &mut state.lock().await;
+    if !is_authorized(&headers, &state.token) {
+        return Response::builder()
+            .status(StatusCode::UNAUTHORIZED)
+            .body(body::Empty::new())
+            .unwrap()
+            .into_response();
+    }
+
+    info!("Getting devices");
+    match state.spot.get_devices().await {
+        Ok(devices) => json(&options, &devices),
         Err(_) => Response::builder()
+            .status(StatusCode::INTERNAL_SERVER_ERROR)
+            .body(body::Empty::new())
+            .unwrap()
+            .into_response(),
+    }
+}
+
+/// The playback state flagging when playback moved to another device since
+/// it was last returned, so a widget can say why playback jumped
+#[instrument(skip(state, headers))]
+async fn get_playback_state(
+    Query(options): Query<ResponseOptions>,
+    Extension(state): Extension<SharedState>,
+    headers: HeaderMap,
+) -> Response {
+    let state = &mut state.lock().await;
+    if !is_authorized(&headers, &state.token) {
+        return Response::builder()
+            .status(StatusCode::UNAUTHORIZED)
+            .body(body::Empty::new())
+            .unwrap()
+            .into_response();
+    }
+
+    info!("Getting playback state");
+    match state.spot.get_playback_state().await {
+        Ok(Some(mut playback_state)) => {
+            let device_id = playback_state.device.id.clone();
+            playback_state.device_changed =
+                state.last_device_id.is_some() && state.last_device_id != device_id;
+            state.last_device_id = device_id;
+            json(&options, &playback_state)
+        }
+        Ok(None) => Response::builder()
             .status(StatusCode::NO_CONTENT)
             .body(body::Empty::new())
             .unwrap()
             .into_response(),
+        Err(_) => Response::builder()
+            .status(StatusCode::INTERNAL_SERVER_ERROR)
+            .body(body::Empty::new())
+            .unwrap()
+            .into_response(),
     }
 }
 
-// Make our own error that wraps `anyhow::Error`.
-struct AppError(anyhow::Error);
+#[instrument(skip(state, headers))]
+async fn get_scopes(
+    Query(options): Query<ResponseOptions>,
+    Extension(state): Extension<SharedState>,
+    headers: HeaderMap,
+) -> Response {
+    let state = &mut state.lock().await;
+    if !is_authorized(&headers, &state.token) {
+        return Response::builder()
+            .status(StatusCode::UNAUTHORIZED)
+            .body(body::Empty::new())
+            .unwrap()
+            .into_response();
+    }
 
-// Tell axum how to convert `AppError` into a response.
-impl IntoResponse for AppError {
-    fn into_response(self) -> Response {
-        (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            format!("Something went wrong: {}", self.0),
-        )
-            .into_response()
+    info!("Getting token scopes");
+    match state.spot.get_scopes().await {
+        Ok(scopes) => json(&options, &scopes),
+        Err(err) => Response::builder()
+            .status(spot_error_status(&err))
+            .body(body::Empty::new())
+            .unwrap()
+            .into_response(),
     }
 }
 
-// This enables using `?` on functions that return `Result<_, anyhow::Error>` to turn them into
-// `Result<_, AppError>`. That way you don't need to do that manually.
-impl<E> From<E> for AppError
-where
-    E: Into<anyhow::Error>,
-{
-    fn from(err: E) -> Self {
-        Self(err.into())
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct DeviceNotFound {
+    available_devices: Vec<String>,
+}
+
+#[instrument(skip(state, headers))]
+async fn play_on_device(
+    Query(query): Query<PlayOnDeviceQuery>,
+    Query(options): Query<ResponseOptions>,
+    Query(dry_run_query): Query<DryRunQuery>,
+    Extension(state): Extension<SharedState>,
+    headers: HeaderMap,
+) -> Response {
+    let state = &mut state.lock().await;
+    if !is_authorized(&headers, &state.token) {
+        return Response::builder()
+            .status(StatusCode::UNAUTHORIZED)
+            .body(body::Empty::new())
+            .unwrap()
+            .into_response();
+    }
+
+    let devices = match state.spot.get_devices().await {
+        Ok(devices) => devices,
+        Err(_) => {
+            return Response::builder()
+                .status(StatusCode::INTERNAL_SERVER_ERROR)
+                .body(body::Empty::new())
+                .unwrap()
+                .into_response()
+        }
+    };
+
+    let device_id = devices
+        .iter()
+        .find(|device| device.name.eq_ignore_ascii_case(&query.device_name))
+        .and_then(|device| device.id.clone());
+    let device_id = match device_id {
+        Some(device_id) => device_id,
+        None => {
+            return (
+                StatusCode::NOT_FOUND,
+                json(
+                    &options,
+                    &DeviceNotFound {
+                        available_devices: devices.into_iter().map(|device| device.name).collect(),
+                    },
+                ),
+            )
+                .into_response()
+        }
+    };
+
+    if dry_run_query.dry_run {
+        return json(
+            &options,
+            &PlannedAction {
+                action: "play".into(),
+                device_name: Some(query.device_name),
+                uri: query.uri,
+                position_ms: None,
+                shuffle: None,
+                repeat_state: None,
+                device_id: Some(device_id),
+            },
+        );
+    }
+
+    info!(device_id, uri = query.uri, "Playing on device");
+    let status = match state
+        .spot
+        .play(query.uri.as_deref(), Some(&device_id), None)
+        .await
+    {
+        Ok(_) => StatusCode::OK,
+        Err(error) => spot_error_status(&error),
+    };
+
+    Response::builder()
+        .status(status)
+        .body(body::Empty::new())
+        .unwrap()
+        .into_response()
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct RepeatState {
+    repeat_state: RepeatMode,
+}
+
+/// Moves the repeat mode on to the next one, off, context, track and back to
+/// off, answering with the mode it's set to now
+#[instrument(skip(state, headers))]
+async fn cycle_repeat(
+    Query(options): Query<ResponseOptions>,
+    Query(dry_run_query): Query<DryRunQuery>,
+    Extension(state): Extension<SharedState>,
+    headers: HeaderMap,
+) -> Response {
+    let state = &mut state.lock().await;
+    if !is_authorized(&headers, &state.token) {
+        return Response::builder()
+            .status(StatusCode::UNAUTHORIZED)
+            .body(body::Empty::new())
+            .unwrap()
+            .into_response();
+    }
+
+    let repeat_state = match state.spot.get_playback_state().await {
+        Ok(Some(playback_state)) => playback_state.repeat_state.next(),
+        Ok(None) => {
+            return Response::builder()
+                .status(StatusCode::NOT_FOUND)
+                .body(body::Empty::new())
+                .unwrap()
+                .into_response()
+        }
+        Err(_) => {
+            return Response::builder()
+                .status(StatusCode::INTERNAL_SERVER_ERROR)
+                .body(body::Empty::new())
+                .unwrap()
+                .into_response()
+        }
+    };
+
+    if dry_run_query.dry_run {
+        let action = PlannedAction {
+            action: "repeat".into(),
+            repeat_state: Some(repeat_state),
+            ..Default::default()
+        };
+        return dry_run(&mut state.spot, &options, action).await;
+    }
+
+    info!(%repeat_state, "Setting repeat mode");
+    match state.spot.set_repeat(repeat_state).await {
+        Ok(_) => json(&options, &RepeatState { repeat_state }),
+        Err(error) => Response::builder()
+            .status(spot_error_status(&error))
+            .body(body::Empty::new())
+            .unwrap()
+            .into_response(),
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct AddRecommendationsQuery {
+    count: Option<usize>,
+}
+
+/// Queues recommendations seeded from the current track, `409` when no track
+/// is playing to seed them from
+#[instrument(skip(state, headers))]
+async fn add_recommendations(
+    Query(query): Query<AddRecommendationsQuery>,
+    Query(options): Query<ResponseOptions>,
+    Query(dry_run_query): Query<DryRunQuery>,
+    Extension(state): Extension<SharedState>,
+    headers: HeaderMap,
+) -> Response {
+    let state = &mut state.lock().await;
+    if !is_authorized(&headers, &state.token) {
+        return Response::builder()
+            .status(StatusCode::UNAUTHORIZED)
+            .body(body::Empty::new())
+            .unwrap()
+            .into_response();
+    }
+
+    // Every track is queued with a request of its own, so only a handful at once
+    let count = query.count.unwrap_or(5);
+    if !(1..=20).contains(&count) {
+        return Response::builder()
+            .status(StatusCode::BAD_REQUEST)
+            .body(body::Empty::new())
+            .unwrap()
+            .into_response();
+    }
+
+    if dry_run_query.dry_run {
+        let action = PlannedAction {
+            action: "add-recommendations".into(),
+            ..Default::default()
+        };
+        return dry_run(&mut state.spot, &options, action).await;
+    }
+
+    info!(count, "Queueing recommendations");
+    match state.spot.queue_recommendations(count).await {
+        Ok(Some(tracks)) => json(&options, &tracks),
+        Ok(None) => Response::builder()
+            .status(StatusCode::CONFLICT)
+            .body(body::Empty::new())
+            .unwrap()
+            .into_response(),
+        Err(error) => Response::builder()
+            .status(spot_error_status(&error))
+            .body(body::Empty::new())
+            .unwrap()
+            .into_response(),
+    }
+}
+
+#[instrument(skip(state, headers))]
+async fn play_liked(
+    Query(options): Query<ResponseOptions>,
+    Query(dry_run_query): Query<DryRunQuery>,
+    Extension(state): Extension<SharedState>,
+    headers: HeaderMap,
+) -> Response {
+    let state = &mut state.lock().await;
+    if !is_authorized(&headers, &state.token) {
+        return Response::builder()
+            .status(StatusCode::UNAUTHORIZED)
+            .body(body::Empty::new())
+            .unwrap()
+            .into_response();
+    }
+
+    if dry_run_query.dry_run {
+        let uri = match state.spot.liked_songs_uri().await {
+            Ok(uri) => uri,
+            Err(error) => {
+                return Response::builder()
+                    .status(spot_error_status(&error))
+                    .body(body::Empty::new())
+                    .unwrap()
+                    .into_response()
+            }
+        };
+        let action = PlannedAction {
+            action: "play".into(),
+            uri: Some(uri),
+            ..Default::default()
+        };
+        return dry_run(&mut state.spot, &options, action).await;
+    }
+
+    info!("Playing liked songs");
+    let status = match state.spot.play_liked().await {
+        Ok(_) => StatusCode::OK,
+        Err(error) => spot_error_status(&error),
+    };
+
+    Response::builder()
+        .status(status)
+        .body(body::Empty::new())
+        .unwrap()
+        .into_response()
+}
+
+#[derive(Deserialize, Debug)]
+struct PlaylistTrackQuery {
+    uri: String,
+}
+
+#[instrument(skip(state, headers))]
+async fn remove_from_playlist(
+    Path(playlist_id): Path<String>,
+    Query(query): Query<PlaylistTrackQuery>,
+    Extension(state): Extension<SharedState>,
+    headers: HeaderMap,
+) -> Response {
+    let state = &mut state.lock().await;
+    if !is_authorized(&headers, &state.token) {
+        return Response::builder()
+            .status(StatusCode::UNAUTHORIZED)
+            .body(body::Empty::new())
+            .unwrap()
+            .into_response();
+    }
+
+    if !is_spotify_id(&playlist_id) {
+        return Response::builder()
+            .status(StatusCode::BAD_REQUEST)
+            .body(body::Empty::new())
+            .unwrap()
+            .into_response();
+    }
+
+    info!(%playlist_id, uri = query.uri, "Removing track from playlist");
+    let status = match state
+        .spot
+        .remove_from_playlist(&playlist_id, &query.uri)
+        .await
+    {
+        Ok(_) => StatusCode::OK,
+        Err(error) => spot_error_status(&error),
+    };
+
+    Response::builder()
+        .status(status)
+        .body(body::Empty::new())
+        .unwrap()
+        .into_response()
+}
+
+#[derive(Deserialize, Debug)]
+struct ReorderPlaylist {
+    range_start: usize,
+    insert_before: usize,
+    range_length: Option<usize>,
+}
+
+#[instrument(skip(state, headers))]
+async fn reorder_playlist(
+    Path(playlist_id): Path<String>,
+    Extension(state): Extension<SharedState>,
+    headers: HeaderMap,
+    Json(reorder): Json<ReorderPlaylist>,
+) -> Response {
+    let state = &mut state.lock().await;
+    if !is_authorized(&headers, &state.token) {
+        return Response::builder()
+            .status(StatusCode::UNAUTHORIZED)
+            .body(body::Empty::new())
+            .unwrap()
+            .into_response();
+    }
+
+    if !is_spotify_id(&playlist_id) {
+        return Response::builder()
+            .status(StatusCode::BAD_REQUEST)
+            .body(body::Empty::new())
+            .unwrap()
+            .into_response();
+    }
+
+    // Playlists the account doesn't follow aren't cached, Spotify checks those
+    let range_length = reorder.range_length.unwrap_or(1);
+    let length = state
+        .spot
+        .get_playlist_length(&playlist_id)
+        .await
+        .ok()
+        .flatten();
+    let range_end = reorder.range_start.checked_add(range_length);
+    let in_range = range_length > 0
+        && range_end.is_some_and(|range_end| {
+            length.is_none_or(|length| range_end <= length && reorder.insert_before <= length)
+        });
+    if !in_range {
+        return Response::builder()
+            .status(StatusCode::BAD_REQUEST)
+            .body(body::Empty::new())
+            .unwrap()
+            .into_response();
+    }
+
+    info!(%playlist_id, ?reorder, "Reordering playlist");
+    let status = match state
+        .spot
+        .reorder_playlist(
+            &playlist_id,
+            reorder.range_start,
+            reorder.insert_before,
+            range_length,
+        )
+        .await
+    {
+        Ok(_) => StatusCode::OK,
+        Err(error) => spot_error_status(&error),
+    };
+
+    Response::builder()
+        .status(status)
+        .body(body::Empty::new())
+        .unwrap()
+        .into_response()
+}
+
+#[instrument(skip(state, headers))]
+async fn get_cache_status(
+    Query(options): Query<ResponseOptions>,
+    Extension(state): Extension<SharedState>,
+    headers: HeaderMap,
+) -> Response {
+    let state = &state.lock().await;
+    if !is_authorized(&headers, &state.token) {
+        return Response::builder()
+            .status(StatusCode::UNAUTHORIZED)
+            .body(body::Empty::new())
+            .unwrap()
+            .into_response();
+    }
+
+    json(&options, &state.spot.cache_status())
+}
+
+#[instrument(skip(state, headers))]
+async fn clear_caches(
+    Query(options): Query<ResponseOptions>,
+    Extension(state): Extension<SharedState>,
+    headers: HeaderMap,
+) -> Response {
+    let state = &mut state.lock().await;
+    if !is_authorized(&headers, &state.token) {
+        return Response::builder()
+            .status(StatusCode::UNAUTHORIZED)
+            .body(body::Empty::new())
+            .unwrap()
+            .into_response();
+    }
+
+    let cleared = state.spot.clear_caches();
+    info!(?cleared, "Cleared caches");
+    json(&options, &cleared)
+}
+
+/// The warm caches for the next instance to import before taking traffic on
+/// a redeploy
+#[instrument(skip(state, headers))]
+async fn export_caches(
+    Query(options): Query<ResponseOptions>,
+    Extension(state): Extension<SharedState>,
+    headers: HeaderMap,
+) -> Response {
+    let state = &state.lock().await;
+    if !is_authorized(&headers, &state.token) {
+        return Response::builder()
+            .status(StatusCode::UNAUTHORIZED)
+            .body(body::Empty::new())
+            .unwrap()
+            .into_response();
+    }
+
+    info!("Exporting caches");
+    // Always camelCase, the import reads it back as such
+    let options = ResponseOptions {
+        case: Case::Camel,
+        ..options
+    };
+    json(&options, &state.spot.export_caches())
+}
+
+#[instrument(skip(state, headers, snapshot))]
+async fn import_caches(
+    Query(options): Query<ResponseOptions>,
+    Extension(state): Extension<SharedState>,
+    headers: HeaderMap,
+    Json(snapshot): Json<CacheSnapshot>,
+) -> Response {
+    let state = &mut state.lock().await;
+    if !is_authorized(&headers, &state.token) {
+        return Response::builder()
+            .status(StatusCode::UNAUTHORIZED)
+            .body(body::Empty::new())
+            .unwrap()
+            .into_response();
+    }
+
+    let imported = state.spot.import_caches(snapshot);
+    info!(?imported, "Imported caches");
+    json(&options, &imported)
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct AdminStatus {
+    /// `None` when the profile couldn't be fetched
+    display_name: Option<String>,
+    read_only: bool,
+    caches: CacheStatus,
+    metrics: MetricsSnapshot,
+}
+
+/// Everything worth checking on the running server in one response
+#[instrument(skip(state, metrics, headers))]
+async fn get_admin_status(
+    Query(options): Query<ResponseOptions>,
+    Extension(state): Extension<SharedState>,
+    Extension(metrics): Extension<Arc<Metrics>>,
+    headers: HeaderMap,
+) -> Response {
+    let state = &mut state.lock().await;
+    if !is_authorized(&headers, &state.token) {
+        return Response::builder()
+            .status(StatusCode::UNAUTHORIZED)
+            .body(body::Empty::new())
+            .unwrap()
+            .into_response();
+    }
+
+    let display_name = state
+        .spot
+        .get_profile()
+        .await
+        .ok()
+        .and_then(|profile| profile.display_name);
+
+    json(
+        &options,
+        &AdminStatus {
+            display_name,
+            read_only: state.read_only,
+            caches: state.spot.cache_status(),
+            metrics: metrics.snapshot(),
+        },
+    )
+}
+
+#[derive(Deserialize)]
+struct CurrentSongQuery {
+    types: Option<String>,
+    fields: Option<String>,
+}
+
+/// Links the page `/` shows browsers to the frontend
+#[derive(Clone)]
+struct FrontendUrl(Option<String>);
+
+#[instrument(skip(state, query, frontend_url, headers))]
+async fn get_current_song(
+    Extension(state): Extension<SharedState>,
+    Extension(frontend_url): Extension<FrontendUrl>,
+    query: Option<Query<CurrentSongQuery>>,
+    Query(options): Query<ResponseOptions>,
+    headers: HeaderMap,
+) -> Response {
+    if prefers_html(&headers) {
+        return current_song_page(state, frontend_url).await;
+    }
+
+    let (types, fields) = match query {
+        Some(Query(query)) => (query.types, query.fields),
+        None => (None, None),
+    };
+    let additional_types = match types {
+        Some(types) => match types
+            .split(',')
+            .map(|additional_type| additional_type.parse::<AdditionalType>())
+            .collect::<Result<Vec<AdditionalType>, _>>()
+        {
+            Ok(additional_types) => additional_types,
+            Err(_) => {
+                return Response::builder()
+                    .status(StatusCode::BAD_REQUEST)
+                    .body(body::Empty::new())
+                    .unwrap()
+                    .into_response()
+            }
+        },
+        None => vec![AdditionalType::Track],
+    };
+
+    let spot = &mut state.lock().await.spot;
+    info!("Getting current song ",);
+    match spot.get_current_song(&additional_types).await {
+        Ok(song) => match (fields, serde_json::to_value(&song)) {
+            // Projected after the case conversion, so `fields` is in the requested case
+            (Some(fields), Ok(song)) => {
+                let song = match options.case {
+                    Case::Camel => song,
+                    Case::Snake => to_snake_case(song),
+                };
+                json(&options, &project(&song, &fields))
+            }
+            _ => json(&options, &song),
+        },
+        Err(_) => Response::builder()
+            .status(StatusCode::INTERNAL_SERVER_ERROR)
+            .body(body::Empty::new())
+            .unwrap()
+            .into_response(),
+    }
+}
+
+#[instrument(skip(state))]
+async fn get_next_up(
+    Extension(state): Extension<SharedState>,
+    Query(options): Query<ResponseOptions>,
+) -> Response {
+    let spot = &mut state.lock().await.spot;
+    info!("Getting next up");
+    match spot.get_queue().await {
+        Ok(queue) => match queue.first() {
+            Some(next) => json(&options, next),
+            None => Response::builder()
+                .status(StatusCode::NO_CONTENT)
+                .body(body::Empty::new())
+                .unwrap()
+                .into_response(),
+        },
+        Err(_) => Response::builder()
+            .status(StatusCode::INTERNAL_SERVER_ERROR)
+            .body(body::Empty::new())
+            .unwrap()
+            .into_response(),
+    }
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct Canvas {
+    canvas_url: String,
+}
+
+#[instrument(skip(state))]
+async fn get_canvas(
+    Extension(state): Extension<SharedState>,
+    Query(options): Query<ResponseOptions>,
+) -> Response {
+    let spot = &mut state.lock().await.spot;
+    info!("Getting canvas");
+    match spot.get_canvas().await {
+        Ok(Some(canvas_url)) => json(&options, &Canvas { canvas_url }),
+        Ok(None) => Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(body::Empty::new())
+            .unwrap()
+            .into_response(),
+        Err(_) => Response::builder()
+            .status(StatusCode::INTERNAL_SERVER_ERROR)
+            .body(body::Empty::new())
+            .unwrap()
+            .into_response(),
+    }
+}
+
+#[instrument(skip(state))]
+async fn get_synced_lyrics(
+    Extension(state): Extension<SharedState>,
+    Query(options): Query<ResponseOptions>,
+) -> Response {
+    let spot = &mut state.lock().await.spot;
+    info!("Getting synced lyrics");
+    match spot.get_synced_lyrics().await {
+        Ok(Some(lyrics)) => json(&options, &lyrics),
+        Ok(None) => Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(body::Empty::new())
+            .unwrap()
+            .into_response(),
+        Err(_) => Response::builder()
+            .status(StatusCode::INTERNAL_SERVER_ERROR)
+            .body(body::Empty::new())
+            .unwrap()
+            .into_response(),
+    }
+}
+
+#[instrument(skip(state))]
+async fn get_history(
+    Extension(state): Extension<SharedState>,
+    Query(options): Query<ResponseOptions>,
+) -> Response {
+    let spot = &state.lock().await.spot;
+    info!("Getting history");
+    json(&options, &spot.get_history())
+}
+
+#[instrument(skip(state))]
+async fn get_hourly_stats(
+    Query(options): Query<ResponseOptions>,
+    Extension(state): Extension<SharedState>,
+) -> Response {
+    let spot = &state.lock().await.spot;
+    info!("Getting hourly stats");
+    json(&options, &spot.get_hourly_stats())
+}
+
+#[instrument(skip(state))]
+async fn get_taste(
+    Query(options): Query<ResponseOptions>,
+    Extension(state): Extension<SharedState>,
+) -> Response {
+    info!("Getting taste");
+    match state.lock().await.spot.get_taste().await {
+        Ok(taste) => json(&options, &taste),
+        Err(_) => Response::builder()
+            .status(StatusCode::INTERNAL_SERVER_ERROR)
+            .body(body::Empty::new())
+            .unwrap()
+            .into_response(),
+    }
+}
+
+#[derive(Deserialize)]
+struct TopSongsQuery {
+    limit: Option<usize>,
+}
+
+#[derive(Deserialize, Debug)]
+struct CurrentArtQuery {
+    #[serde(default)]
+    size: ImageSize,
+}
+
+#[instrument(skip(state, headers))]
+async fn get_current_art(
+    Extension(state): Extension<SharedState>,
+    Query(query): Query<CurrentArtQuery>,
+    headers: HeaderMap,
+) -> Response {
+    let image = {
+        let spot = &mut state.lock().await.spot;
+        match spot.get_current_song(&[AdditionalType::Track]).await {
+            Ok(Some(current_song)) => current_song.image(query.size).cloned(),
+            Ok(None) => None,
+            Err(_) => {
+                return Response::builder()
+                    .status(StatusCode::INTERNAL_SERVER_ERROR)
+                    .body(body::Empty::new())
+                    .unwrap()
+                    .into_response()
+            }
+        }
+    };
+
+    let image = match image {
+        Some(image) => image,
+        None => {
+            return Response::builder()
+                .status(StatusCode::NO_CONTENT)
+                .body(body::Empty::new())
+                .unwrap()
+                .into_response()
+        }
+    };
+
+    // The art of an album never changes, but the album behind this route does. So
+    // clients revalidate every time and only download the art once the album changed.
+    let etag = format!("\"{:}\"", image.id());
+    if matches!(headers.get(header::IF_NONE_MATCH), Some(if_none_match) if if_none_match == etag.as_str())
+    {
+        return Response::builder()
+            .status(StatusCode::NOT_MODIFIED)
+            .header(header::ETAG, etag)
+            .body(body::Empty::new())
+            .unwrap()
+            .into_response();
+    }
+
+    info!(url = image.url, "Getting current album art");
+    let res = download::client().get(&image.url).send().await;
+    let response = match res {
+        Ok(response) if download::too_large(&response, palette::MAX_ART_BYTES) => {
+            error!(?response, "Album art too large");
+            return Response::builder()
+                .status(StatusCode::BAD_GATEWAY)
+                .body(body::Empty::new())
+                .unwrap()
+                .into_response();
+        }
+        Ok(response) if response.status().is_success() => response,
+        _ => {
+            error!(?res, "Could not get album art");
+            return Response::builder()
+                .status(StatusCode::BAD_GATEWAY)
+                .body(body::Empty::new())
+                .unwrap()
+                .into_response();
+        }
+    };
+
+    let content_type = response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .cloned()
+        .unwrap_or(HeaderValue::from_static("image/jpeg"));
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, content_type)
+        .header(header::CACHE_CONTROL, "public, no-cache")
+        .header(header::ETAG, etag)
+        .body(StreamBody::new(download::capped_stream(
+            response,
+            palette::MAX_ART_BYTES,
+        )))
+        .unwrap()
+        .into_response()
+}
+
+/// Open Graph tags of the current song, so a shared link unfurls into it
+#[instrument(skip(state))]
+async fn get_open_graph(Extension(state): Extension<SharedState>) -> Response {
+    let song = state
+        .lock()
+        .await
+        .spot
+        .get_current_song(&[AdditionalType::Track, AdditionalType::Episode])
+        .await
+        .ok()
+        .flatten();
+
+    let title = song
+        .as_ref()
+        .and_then(|song| song.item.name().map(String::from));
+    let description = song.as_ref().and_then(|song| song.item.byline());
+    let image = song
+        .as_ref()
+        .and_then(|song| song.image(ImageSize::Large))
+        .map(|image| image.url.clone());
+
+    let mut tags = vec![
+        ("og:type", "music.song".to_string()),
+        ("og:title", title.unwrap_or("Nothing playing".into())),
+        (
+            "og:description",
+            description.unwrap_or("Not listening to anything right now".into()),
+        ),
+    ];
+    if let Some(image) = image {
+        tags.push(("og:image", image));
+    }
+
+    let html = tags
+        .iter()
+        .map(|(property, content)| {
+            format!(
+                "<meta property=\"{:}\" content=\"{:}\" />\n",
+                property,
+                escape_html(content)
+            )
+        })
+        .collect::<String>();
+    ([(header::CACHE_CONTROL, "public, max-age=10")], Html(html)).into_response()
+}
+
+/// Whether the `Accept` header ranks html above json, like browsers navigating
+/// somewhere do. Without a header it's an api client.
+fn prefers_html(headers: &HeaderMap) -> bool {
+    let Some(Ok(accept)) = headers.get(header::ACCEPT).map(|accept| accept.to_str()) else {
+        return false;
+    };
+
+    let quality = |media_type: &str| {
+        accept
+            .split(',')
+            .filter_map(|range| {
+                let mut params = range.split(';').map(str::trim);
+                let range = params.next()?;
+                (range == media_type).then(|| {
+                    params
+                        .find_map(|param| param.strip_prefix("q="))
+                        .and_then(|quality| quality.parse::<f32>().ok())
+                        .unwrap_or(1.0)
+                })
+            })
+            .next()
+    };
+    let html = quality("text/html").unwrap_or(0.0);
+    let json = quality("application/json")
+        .or(quality("*/*"))
+        .unwrap_or(0.0);
+    html > json
+}
+
+/// A page showing the current song, for whoever opens the api in a browser
+async fn current_song_page(state: SharedState, frontend_url: FrontendUrl) -> Response {
+    let song = state
+        .lock()
+        .await
+        .spot
+        .get_current_song(&[AdditionalType::Track, AdditionalType::Episode])
+        .await
+        .ok()
+        .flatten();
+
+    let mut html = String::from(
+        "<!doctype html>\n<html>\n<head>\n<meta charset=\"utf-8\" />\n\
+         <meta name=\"viewport\" content=\"width=device-width\" />\n<title>spot</title>\n\
+         <style>body { font-family: sans-serif; text-align: center; margin-top: 4rem; } \
+         img { width: 300px; border-radius: 8px; }</style>\n</head>\n<body>\n",
+    );
+    match song
+        .as_ref()
+        .and_then(|song| song.item.name().map(|name| (song, name)))
+    {
+        Some((song, name)) => {
+            if let Some(image) = song.image(ImageSize::Large) {
+                html.push_str(&format!(
+                    "<img src=\"{:}\" alt=\"\" />\n",
+                    escape_html(&image.url)
+                ));
+            }
+            html.push_str(&format!("<h1>{}</h1>\n", escape_html(name)));
+            if let Some(byline) = song.item.byline() {
+                html.push_str(&format!("<p>{}</p>\n", escape_html(&byline)));
+            }
+            if !song.is_playing {
+                html.push_str("<p>Paused</p>\n");
+            }
+        }
+        None => html.push_str("<h1>Nothing playing</h1>\n"),
+    }
+    if let FrontendUrl(Some(url)) = &frontend_url {
+        html.push_str(&format!(
+            "<p><a href=\"{:}\">Open the frontend</a></p>\n",
+            escape_html(url)
+        ));
+    }
+    html.push_str("</body>\n</html>\n");
+    Html(html).into_response()
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+#[instrument(skip(state))]
+async fn get_current_palette(
+    Query(options): Query<ResponseOptions>,
+    Extension(state): Extension<SharedState>,
+) -> Response {
+    info!("Getting current palette");
+    let image_url = {
+        let spot = &mut state.lock().await.spot;
+        match spot.get_palette_art_url().await {
+            Ok(Some(image_url)) => match spot.cached_palette(&image_url) {
+                Some(palette) => return json(&options, &palette),
+                None => image_url,
+            },
+            Ok(None) => {
+                return Response::builder()
+                    .status(StatusCode::NO_CONTENT)
+                    .body(body::Empty::new())
+                    .unwrap()
+                    .into_response()
+            }
+            Err(_) => {
+                return Response::builder()
+                    .status(StatusCode::BAD_GATEWAY)
+                    .body(body::Empty::new())
+                    .unwrap()
+                    .into_response()
+            }
+        }
+    };
+
+    // Downloaded and decoded without the lock, other requests don't wait on the art
+    match palette::fetch(&image_url).await {
+        Ok(palette) => {
+            state
+                .lock()
+                .await
+                .spot
+                .cache_palette(image_url, palette.clone());
+            json(&options, &palette)
+        }
+        Err(_) => Response::builder()
+            .status(StatusCode::BAD_GATEWAY)
+            .body(body::Empty::new())
+            .unwrap()
+            .into_response(),
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct EndsIn {
+    ends_in_ms: i64,
+    #[serde(serialize_with = "serialize_rfc3339")]
+    ends_at_iso: i64,
+}
+
+/// When the current song ends, so frontends can fetch the next one right then
+/// instead of polling. `204` when nothing is playing or it's paused, as it
+/// won't end on its own.
+#[instrument(skip(state))]
+async fn get_current_ends_in(
+    Query(options): Query<ResponseOptions>,
+    Extension(state): Extension<SharedState>,
+) -> Response {
+    let spot = &mut state.lock().await.spot;
+    info!("Getting when the current song ends");
+    match spot
+        .get_current_song(&[AdditionalType::Track, AdditionalType::Episode])
+        .await
+    {
+        Ok(Some(current_song)) if current_song.is_playing => {
+            let ends_in_ms = current_song.remaining_ms();
+            json(
+                &options,
+                &EndsIn {
+                    ends_in_ms,
+                    ends_at_iso: chrono::Utc::now().timestamp_millis() + ends_in_ms,
+                },
+            )
+        }
+        Ok(_) => Response::builder()
+            .status(StatusCode::NO_CONTENT)
+            .body(body::Empty::new())
+            .unwrap()
+            .into_response(),
+        Err(_) => Response::builder()
+            .status(StatusCode::INTERNAL_SERVER_ERROR)
+            .body(body::Empty::new())
+            .unwrap()
+            .into_response(),
+    }
+}
+
+#[instrument(skip(state))]
+async fn get_audio_analysis(
+    Path(track_id): Path<String>,
+    Query(options): Query<ResponseOptions>,
+    Extension(state): Extension<SharedState>,
+) -> Response {
+    if !is_spotify_id(&track_id) {
+        return Response::builder()
+            .status(StatusCode::BAD_REQUEST)
+            .body(body::Empty::new())
+            .unwrap()
+            .into_response();
+    }
+
+    info!("Getting audio analysis");
+    match state.lock().await.spot.get_audio_analysis(&track_id).await {
+        Ok(analysis) => json(&options, &analysis),
+        Err(_) => Response::builder()
+            .status(StatusCode::INTERNAL_SERVER_ERROR)
+            .body(body::Empty::new())
+            .unwrap()
+            .into_response(),
+    }
+}
+
+// 30 second previews are around half a megabyte
+const MAX_PREVIEW_BYTES: usize = 4 * 1024 * 1024;
+
+/// Streams the preview of a track for clients that can't load it from Spotify
+#[instrument(skip(state))]
+async fn get_preview(
+    Path(track_id): Path<String>,
+    Extension(state): Extension<SharedState>,
+) -> Response {
+    if !is_spotify_id(&track_id) {
+        return Response::builder()
+            .status(StatusCode::BAD_REQUEST)
+            .body(body::Empty::new())
+            .unwrap()
+            .into_response();
+    }
+
+    let preview_url = {
+        let spot = &mut state.lock().await.spot;
+        match spot.get_preview_url(&track_id).await {
+            Ok(preview_url) => preview_url,
+            Err(_) => {
+                return Response::builder()
+                    .status(StatusCode::INTERNAL_SERVER_ERROR)
+                    .body(body::Empty::new())
+                    .unwrap()
+                    .into_response()
+            }
+        }
+    };
+
+    let preview_url = match preview_url {
+        Some(preview_url) => preview_url,
+        None => {
+            return Response::builder()
+                .status(StatusCode::NOT_FOUND)
+                .body(body::Empty::new())
+                .unwrap()
+                .into_response()
+        }
+    };
+
+    info!(url = preview_url, "Getting preview");
+    let res = download::client().get(&preview_url).send().await;
+    let response = match res {
+        Ok(response) if download::too_large(&response, MAX_PREVIEW_BYTES) => {
+            error!(?response, "Preview too large");
+            return Response::builder()
+                .status(StatusCode::BAD_GATEWAY)
+                .body(body::Empty::new())
+                .unwrap()
+                .into_response();
+        }
+        Ok(response) if response.status().is_success() => response,
+        _ => {
+            error!(?res, "Could not get preview");
+            return Response::builder()
+                .status(StatusCode::BAD_GATEWAY)
+                .body(body::Empty::new())
+                .unwrap()
+                .into_response();
+        }
+    };
+
+    let content_type = response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .cloned()
+        .unwrap_or(HeaderValue::from_static("audio/mpeg"));
+    // The preview of a track never changes
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, content_type)
+        .header(header::CACHE_CONTROL, "public, max-age=86400")
+        .body(StreamBody::new(download::capped_stream(
+            response,
+            MAX_PREVIEW_BYTES,
+        )))
+        .unwrap()
+        .into_response()
+}
+
+#[derive(Deserialize, Debug)]
+struct ArtistsQuery {
+    ids: String,
+}
+
+const MAX_ARTIST_IDS: usize = 50;
+
+#[instrument(skip(state))]
+async fn get_artists(
+    Extension(state): Extension<SharedState>,
+    Query(query): Query<ArtistsQuery>,
+    Query(options): Query<ResponseOptions>,
+) -> Response {
+    let ids = query
+        .ids
+        .split(',')
+        .filter(|id| !id.is_empty())
+        .map(String::from)
+        .collect::<Vec<String>>();
+    if ids.is_empty() || ids.len() > MAX_ARTIST_IDS {
+        return Response::builder()
+            .status(StatusCode::BAD_REQUEST)
+            .body(body::Empty::new())
+            .unwrap()
+            .into_response();
+    }
+
+    let spot = &mut state.lock().await.spot;
+    info!("Getting artists");
+    match spot.get_artists(&ids).await {
+        Ok(artists) => json(&options, &artists),
+        Err(_) => Response::builder()
+            .status(StatusCode::INTERNAL_SERVER_ERROR)
+            .body(body::Empty::new())
+            .unwrap()
+            .into_response(),
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct FollowedArtistsQuery {
+    limit: Option<usize>,
+}
+
+#[instrument(skip(state))]
+async fn get_followed_artists(
+    Extension(state): Extension<SharedState>,
+    Query(query): Query<FollowedArtistsQuery>,
+    Query(options): Query<ResponseOptions>,
+) -> Response {
+    let limit = query.limit.unwrap_or(20);
+    let spot = &mut state.lock().await.spot;
+    info!("Getting followed artists");
+    match spot.get_followed_artists(limit).await {
+        Ok(artists) => json(&options, &artists),
+        Err(_) => Response::builder()
+            .status(StatusCode::INTERNAL_SERVER_ERROR)
+            .body(body::Empty::new())
+            .unwrap()
+            .into_response(),
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct SavedAlbumsQuery {
+    limit: Option<usize>,
+}
+
+#[instrument(skip(state))]
+async fn get_saved_albums(
+    Extension(state): Extension<SharedState>,
+    Query(query): Query<SavedAlbumsQuery>,
+    Query(options): Query<ResponseOptions>,
+) -> Response {
+    let limit = query.limit.unwrap_or(20);
+    let spot = &mut state.lock().await.spot;
+    info!("Getting saved albums");
+    match spot.get_saved_albums(limit).await {
+        Ok(albums) => json(&options, &albums),
+        Err(_) => Response::builder()
+            .status(StatusCode::INTERNAL_SERVER_ERROR)
+            .body(body::Empty::new())
+            .unwrap()
+            .into_response(),
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct SavedShowsQuery {
+    limit: Option<usize>,
+}
+
+#[instrument(skip(state))]
+async fn get_saved_shows(
+    Extension(state): Extension<SharedState>,
+    Query(query): Query<SavedShowsQuery>,
+    Query(options): Query<ResponseOptions>,
+) -> Response {
+    let limit = query.limit.unwrap_or(20);
+    let spot = &mut state.lock().await.spot;
+    info!("Getting saved shows");
+    match spot.get_saved_shows(limit).await {
+        Ok(shows) => json(&options, &shows),
+        Err(_) => Response::builder()
+            .status(StatusCode::INTERNAL_SERVER_ERROR)
+            .body(body::Empty::new())
+            .unwrap()
+            .into_response(),
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct PlaylistSearchQuery {
+    q: String,
+}
+
+#[instrument(skip(state))]
+async fn search_playlists(
+    Extension(state): Extension<SharedState>,
+    Query(query): Query<PlaylistSearchQuery>,
+    Query(options): Query<ResponseOptions>,
+) -> Response {
+    let spot = &mut state.lock().await.spot;
+    info!("Searching playlists");
+    match spot.search_playlists(&query.q).await {
+        Ok(playlists) => json(&options, &playlists),
+        Err(_) => Response::builder()
+            .status(StatusCode::INTERNAL_SERVER_ERROR)
+            .body(body::Empty::new())
+            .unwrap()
+            .into_response(),
+    }
+}
+
+#[instrument(skip(state))]
+async fn get_playlists_with_current(
+    Extension(state): Extension<SharedState>,
+    Query(options): Query<ResponseOptions>,
+) -> Response {
+    let spot = &mut state.lock().await.spot;
+    info!("Getting playlists with the current track");
+    match spot.get_playlists_with_current().await {
+        Ok(Some(playlists)) => json(&options, &playlists),
+        Ok(None) => Response::builder()
+            .status(StatusCode::NO_CONTENT)
+            .body(body::Empty::new())
+            .unwrap()
+            .into_response(),
+        Err(_) => Response::builder()
+            .status(StatusCode::INTERNAL_SERVER_ERROR)
+            .body(body::Empty::new())
+            .unwrap()
+            .into_response(),
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct LikedTracksQuery {
+    limit: Option<usize>,
+    #[serde(default)]
+    offset: usize,
+}
+
+#[instrument(skip(state))]
+async fn get_liked_tracks(
+    Extension(state): Extension<SharedState>,
+    Query(query): Query<LikedTracksQuery>,
+    Query(options): Query<ResponseOptions>,
+) -> Response {
+    let limit = query.limit.unwrap_or(20);
+    let spot = &mut state.lock().await.spot;
+    info!("Getting liked tracks");
+    match spot.get_liked_tracks(limit, query.offset).await {
+        Ok(tracks) => json(&options, &tracks),
+        Err(_) => Response::builder()
+            .status(StatusCode::INTERNAL_SERVER_ERROR)
+            .body(body::Empty::new())
+            .unwrap()
+            .into_response(),
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct LikedIdsQuery {
+    ids: String,
+}
+
+const MAX_LIKED_IDS: usize = 50;
+
+#[instrument(skip(state))]
+async fn get_liked_ids(
+    Extension(state): Extension<SharedState>,
+    Query(query): Query<LikedIdsQuery>,
+    Query(options): Query<ResponseOptions>,
+) -> Response {
+    let ids = query
+        .ids
+        .split(',')
+        .filter(|id| !id.is_empty())
+        .map(String::from)
+        .collect::<Vec<String>>();
+    if ids.is_empty() || ids.len() > MAX_LIKED_IDS {
+        return Response::builder()
+            .status(StatusCode::BAD_REQUEST)
+            .body(body::Empty::new())
+            .unwrap()
+            .into_response();
+    }
+
+    let spot = &mut state.lock().await.spot;
+    info!("Checking liked tracks");
+    match spot.get_liked_ids(&ids).await {
+        Ok(liked) => json(&options, &liked),
+        Err(_) => Response::builder()
+            .status(StatusCode::INTERNAL_SERVER_ERROR)
+            .body(body::Empty::new())
+            .unwrap()
+            .into_response(),
+    }
+}
+
+#[instrument(skip(state))]
+async fn get_recently_played(
+    Extension(state): Extension<SharedState>,
+    Query(query): Query<RecentlyPlayedQuery>,
+    Query(options): Query<ResponseOptions>,
+) -> Response {
+    let (Ok(cursor), Ok(limit)) = (query.cursor(), query.limit()) else {
+        return Response::builder()
+            .status(StatusCode::BAD_REQUEST)
+            .body(body::Empty::new())
+            .unwrap()
+            .into_response();
+    };
+
+    let spot = &mut state.lock().await.spot;
+    info!("Getting recently played");
+    match spot.get_recently_played(limit, cursor).await {
+        Ok(recently_played) => json(&options, &recently_played),
+        Err(_) => Response::builder()
+            .status(StatusCode::INTERNAL_SERVER_ERROR)
+            .body(body::Empty::new())
+            .unwrap()
+            .into_response(),
+    }
+}
+
+#[instrument(skip(state))]
+async fn get_genre_seeds(
+    Query(options): Query<ResponseOptions>,
+    Extension(state): Extension<SharedState>,
+) -> Response {
+    let spot = &mut state.lock().await.spot;
+    info!("Getting genre seeds");
+    match spot.get_genre_seeds().await {
+        Ok(genres) => json(&options, &genres),
+        Err(_) => Response::builder()
+            .status(StatusCode::INTERNAL_SERVER_ERROR)
+            .body(body::Empty::new())
+            .unwrap()
+            .into_response(),
+    }
+}
+
+const MAX_RECOMMENDATION_SEEDS: usize = 5;
+
+/// Picks the seeds, limit and tunable attributes out of the query, rejecting
+/// tunables outside of the range Spotify accepts
+fn recommendation_params(query: &HashMap<String, String>) -> Option<Vec<(String, String)>> {
+    let mut params = Vec::new();
+    let mut seeds = 0;
+    for (key, value) in query {
+        if ["seed_artists", "seed_genres", "seed_tracks"].contains(&key.as_str()) {
+            seeds += value.split(',').filter(|seed| !seed.is_empty()).count();
+            params.push((key.clone(), value.clone()));
+        } else if key == "limit" {
+            value
+                .parse::<u8>()
+                .ok()
+                .filter(|limit| (1..=100).contains(limit))?;
+            params.push((key.clone(), value.clone()));
+        } else if let Some(attribute) = ["min_", "max_", "target_"]
+            .iter()
+            .find_map(|prefix| key.strip_prefix(prefix))
+        {
+            let (_, min, max) = TUNABLE_ATTRIBUTES
+                .iter()
+                .find(|(name, _, _)| *name == attribute)?;
+            value
+                .parse::<f64>()
+                .ok()
+                .filter(|value| (*min..=*max).contains(value))?;
+            params.push((key.clone(), value.clone()));
+        }
+    }
+
+    if seeds == 0 || seeds > MAX_RECOMMENDATION_SEEDS {
+        return None;
+    }
+
+    Some(params)
+}
+
+#[instrument(skip(state))]
+async fn get_recommendations(
+    Extension(state): Extension<SharedState>,
+    Query(query): Query<HashMap<String, String>>,
+    Query(options): Query<ResponseOptions>,
+) -> Response {
+    let params = match recommendation_params(&query) {
+        Some(params) => params,
+        None => {
+            return Response::builder()
+                .status(StatusCode::BAD_REQUEST)
+                .body(body::Empty::new())
+                .unwrap()
+                .into_response()
+        }
+    };
+
+    let spot = &mut state.lock().await.spot;
+    info!("Getting recommendations");
+    match spot.get_recommendations(&params).await {
+        Ok(tracks) => json(&options, &tracks),
+        Err(_) => Response::builder()
+            .status(StatusCode::INTERNAL_SERVER_ERROR)
+            .body(body::Empty::new())
+            .unwrap()
+            .into_response(),
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct NewReleasesQuery {
+    limit: Option<usize>,
+    country: Option<String>,
+}
+
+#[instrument(skip(state))]
+async fn get_new_releases(
+    Extension(state): Extension<SharedState>,
+    Query(query): Query<NewReleasesQuery>,
+    Query(options): Query<ResponseOptions>,
+) -> Response {
+    let limit = query.limit.unwrap_or(20);
+    let spot = &mut state.lock().await.spot;
+    info!("Getting new releases");
+    match spot.get_new_releases(limit, query.country).await {
+        Ok(albums) => json(&options, &albums),
+        Err(_) => Response::builder()
+            .status(StatusCode::INTERNAL_SERVER_ERROR)
+            .body(body::Empty::new())
+            .unwrap()
+            .into_response(),
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct FeaturedPlaylistsQuery {
+    limit: Option<usize>,
+}
+
+const MAX_FEATURED_PLAYLISTS: usize = 50;
+
+#[instrument(skip(state))]
+async fn get_featured_playlists(
+    Extension(state): Extension<SharedState>,
+    Query(query): Query<FeaturedPlaylistsQuery>,
+    Query(options): Query<ResponseOptions>,
+) -> Response {
+    let limit = query.limit.unwrap_or(10);
+    if limit == 0 || limit > MAX_FEATURED_PLAYLISTS {
+        return Response::builder()
+            .status(StatusCode::BAD_REQUEST)
+            .body(body::Empty::new())
+            .unwrap()
+            .into_response();
+    }
+
+    let spot = &mut state.lock().await.spot;
+    info!("Getting featured playlists");
+    match spot.get_featured_playlists(limit).await {
+        Ok(featured) => json(&options, &featured),
+        Err(_) => Response::builder()
+            .status(StatusCode::INTERNAL_SERVER_ERROR)
+            .body(body::Empty::new())
+            .unwrap()
+            .into_response(),
+    }
+}
+
+#[instrument(skip(state, query))]
+async fn get_top_songs(
+    Extension(state): Extension<SharedState>,
+    query: Option<Query<TopSongsQuery>>,
+    Query(options): Query<ResponseOptions>,
+) -> Response {
+    let limit = query.and_then(|q| q.limit).unwrap_or(4);
+    let spot = &mut state.lock().await.spot;
+    info!("Getting top songs");
+    match spot.get_top_songs(TimeRange::Short).await {
+        Ok(songs) => json(
+            &options,
+            &songs.into_iter().take(limit).collect::<Vec<Item>>(),
+        ),
+        Err(_) => Response::builder()
+            .status(StatusCode::NO_CONTENT)
+            .body(body::Empty::new())
+            .unwrap()
+            .into_response(),
+    }
+}
+
+#[instrument(skip(state, query))]
+async fn get_combined_top_songs(
+    Extension(state): Extension<SharedState>,
+    query: Option<Query<TopSongsQuery>>,
+    Query(options): Query<ResponseOptions>,
+) -> Response {
+    let limit = query.and_then(|q| q.limit).unwrap_or(20);
+    let spot = &mut state.lock().await.spot;
+    info!("Getting combined top songs");
+    match spot.get_combined_top_songs().await {
+        Ok(songs) => json(
+            &options,
+            &songs.into_iter().take(limit).collect::<Vec<Item>>(),
+        ),
+        Err(_) => Response::builder()
+            .status(StatusCode::NO_CONTENT)
+            .body(body::Empty::new())
+            .unwrap()
+            .into_response(),
+    }
+}
+
+#[instrument(skip(state, query))]
+async fn get_top_songs_comparison(
+    Extension(state): Extension<SharedState>,
+    query: Option<Query<TopSongsQuery>>,
+    Query(options): Query<ResponseOptions>,
+) -> Response {
+    let limit = query.and_then(|q| q.limit).unwrap_or(20);
+    let spot = &mut state.lock().await.spot;
+    info!("Comparing top songs");
+    match spot.get_top_songs_comparison(limit).await {
+        Ok(comparison) => json(&options, &comparison),
+        Err(_) => Response::builder()
+            .status(StatusCode::NO_CONTENT)
+            .body(body::Empty::new())
+            .unwrap()
+            .into_response(),
+    }
+}
+
+#[derive(Serialize)]
+struct VersionResponse {
+    version: &'static str,
+    git_sha: &'static str,
+    built_at: &'static str,
+}
+
+#[instrument]
+async fn get_version() -> Json<VersionResponse> {
+    Json(VersionResponse {
+        version: env!("CARGO_PKG_VERSION"),
+        git_sha: env!("GIT_SHA"),
+        built_at: env!("BUILT_AT"),
+    })
+}
+
+#[instrument]
+async fn get_health() -> StatusCode {
+    StatusCode::OK
+}
+
+#[instrument(skip(state))]
+async fn get_spotify_health(
+    Query(options): Query<ResponseOptions>,
+    Extension(state): Extension<SharedState>,
+) -> Response {
+    let spot = &mut state.lock().await.spot;
+    let health = spot.get_health().await;
+    let status = if health.reachable {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+
+    (status, json(&options, &health)).into_response()
+}
+
+#[derive(Serialize)]
+struct ReadyResponse {
+    ready: bool,
+    reason: Option<&'static str>,
+}
+
+#[instrument(skip(state))]
+async fn get_ready(Extension(state): Extension<SharedState>) -> Response {
+    let spot = &mut state.lock().await.spot;
+    let token = if chrono::Utc::now().timestamp() > spot.auth_expires_at {
+        spot.get_token().await
+    } else {
+        Ok(())
+    };
+
+    let (status, reason) = match token {
+        Ok(_) => (StatusCode::OK, None),
+        Err(SpotError::RefreshTokenRevoked) => (
+            StatusCode::SERVICE_UNAVAILABLE,
+            Some("refresh_token_revoked"),
+        ),
+        Err(_) => (StatusCode::SERVICE_UNAVAILABLE, Some("token_unavailable")),
+    };
+
+    (
+        status,
+        Json(ReadyResponse {
+            ready: reason.is_none(),
+            reason,
+        }),
+    )
+        .into_response()
+}
+
+#[instrument(skip(metrics))]
+async fn get_metrics(Extension(metrics): Extension<Arc<Metrics>>) -> String {
+    metrics.render()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+
+    use axum::routing::any;
+
+    use super::*;
+
+    fn origins(origins: &[&str]) -> Vec<String> {
+        origins.iter().map(|origin| origin.to_string()).collect()
+    }
+
+    #[test]
+    fn origins_with_a_scheme_match_exactly() {
+        let allowed_origins = origins(&["https://finndore.dev"]);
+
+        assert!(origin_allowed("https://finndore.dev", &allowed_origins));
+        assert!(!origin_allowed("http://finndore.dev", &allowed_origins));
+        assert!(!origin_allowed(
+            "https://evilfinndore.dev",
+            &allowed_origins
+        ));
+        assert!(!origin_allowed(
+            "https://app.finndore.dev",
+            &allowed_origins
+        ));
+    }
+
+    #[test]
+    fn origins_without_a_scheme_match_the_host_and_its_subdomains() {
+        let allowed_origins = origins(&["finnnn.vercel.app"]);
+
+        assert!(origin_allowed(
+            "https://finnnn.vercel.app",
+            &allowed_origins
+        ));
+        assert!(origin_allowed(
+            "https://preview.finnnn.vercel.app",
+            &allowed_origins
+        ));
+        assert!(!origin_allowed(
+            "https://notfinnnn.vercel.app",
+            &allowed_origins
+        ));
+        assert!(!origin_allowed(
+            "https://finnnn.vercel.app.evil.dev",
+            &allowed_origins
+        ));
+    }
+
+    fn authorization(value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::AUTHORIZATION, HeaderValue::from_str(value).unwrap());
+        headers
+    }
+
+    #[test]
+    fn bare_and_bearer_tokens_are_authorized() {
+        assert!(is_authorized(&authorization("token"), "token"));
+        assert!(is_authorized(&authorization("Bearer token"), "token"));
+        assert!(is_authorized(&authorization("bearer token"), "token"));
+    }
+
+    #[test]
+    fn wrong_or_missing_tokens_are_not_authorized() {
+        assert!(!is_authorized(&HeaderMap::new(), "token"));
+        assert!(!is_authorized(&authorization("other"), "token"));
+        assert!(!is_authorized(&authorization("Bearer other"), "token"));
+        assert!(!is_authorized(&authorization("Basic token"), "token"));
+    }
+
+    /// Headers answering the browser's preflight of an authorized `method` request
+    async fn preflight(method: Method) -> HeaderMap {
+        let mut config = Config::for_tests();
+        config.cors_allowed_origins = origins(&["https://finndore.dev"]);
+        let app = Router::new()
+            .route("/write", any(|| async { StatusCode::OK }))
+            .layer(cors_layer(&config));
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{:}/write", listener.local_addr().unwrap());
+        tokio::spawn(
+            axum::Server::from_tcp(listener)
+                .unwrap()
+                .serve(app.into_make_service()),
+        );
+
+        reqwest::Client::new()
+            .request(Method::OPTIONS, &url)
+            .header(header::ORIGIN, "https://finndore.dev")
+            .header(header::ACCESS_CONTROL_REQUEST_METHOD, method.as_str())
+            .header(header::ACCESS_CONTROL_REQUEST_HEADERS, "authorization")
+            .send()
+            .await
+            .unwrap()
+            .headers()
+            .clone()
+    }
+
+    fn allows(headers: &HeaderMap, name: HeaderName, value: &str) -> bool {
+        headers[name]
+            .to_str()
+            .unwrap()
+            .split(',')
+            .any(|allowed| allowed.trim().eq_ignore_ascii_case(value))
+    }
+
+    #[tokio::test]
+    async fn preflights_of_authenticated_writes_pass() {
+        let headers = preflight(Method::POST).await;
+
+        assert_eq!(
+            headers[header::ACCESS_CONTROL_ALLOW_ORIGIN],
+            "https://finndore.dev"
+        );
+        assert!(allows(
+            &headers,
+            header::ACCESS_CONTROL_ALLOW_METHODS,
+            "POST"
+        ));
+        assert!(allows(
+            &headers,
+            header::ACCESS_CONTROL_ALLOW_HEADERS,
+            "authorization"
+        ));
+    }
+
+    pub(crate) fn test_state() -> SharedState {
+        let config = Config::for_tests();
+        Arc::new(Mutex::new(State {
+            spot: Spot::new(
+                &config,
+                Scheduler::new(config.max_concurrent_spotify_requests),
+                History::new(config.history_max),
+                IdleBackoff::new(config.idle_backoff_max),
+            ),
+            token: config.external_auth_token.clone(),
+            read_only: config.read_only,
+            idempotency_keys: IdempotencyKeys::default(),
+            last_device_id: None,
+        }))
+    }
+
+    #[tokio::test]
+    async fn previews_of_traversing_ids_are_rejected() {
+        let response = get_preview(Path("../me/player".into()), Extension(test_state())).await;
+
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn analyses_of_traversing_ids_are_rejected() {
+        let response = get_audio_analysis(
+            Path("../me/player".into()),
+            Query(ResponseOptions::default()),
+            Extension(test_state()),
+        )
+        .await;
+
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn writes_to_traversing_playlist_ids_are_rejected() {
+        let state = test_state();
+
+        let removed = remove_from_playlist(
+            Path("../me/player".into()),
+            Query(PlaylistTrackQuery {
+                uri: "spotify:track:4cOdK2wGLETKBW3PvgPWqT".into(),
+            }),
+            Extension(state.clone()),
+            authorization("external-token"),
+        )
+        .await;
+        let reordered = reorder_playlist(
+            Path("../me/player".into()),
+            Extension(state),
+            authorization("external-token"),
+            Json(ReorderPlaylist {
+                range_start: 0,
+                insert_before: 1,
+                range_length: None,
+            }),
+        )
+        .await;
+
+        assert_eq!(removed.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(reordered.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn preflights_of_playlist_reorders_pass() {
+        let headers = preflight(Method::PUT).await;
+
+        assert!(allows(
+            &headers,
+            header::ACCESS_CONTROL_ALLOW_METHODS,
+            "PUT"
+        ));
     }
 }
@@ -0,0 +1,105 @@
+use std::fmt::Write;
+
+use serde::Serialize;
+
+use crate::{backoff::IdleBackoff, history::History, scheduler::Scheduler, stream::StreamClients};
+
+pub struct Metrics {
+    scheduler: Scheduler,
+    history: History,
+    stream_clients: StreamClients,
+    idle_backoff: IdleBackoff,
+}
+
+impl Metrics {
+    pub fn new(
+        scheduler: Scheduler,
+        history: History,
+        stream_clients: StreamClients,
+        idle_backoff: IdleBackoff,
+    ) -> Self {
+        Self {
+            scheduler,
+            history,
+            stream_clients,
+            idle_backoff,
+        }
+    }
+
+    /// The same numbers as json, for `/admin/status`
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        MetricsSnapshot {
+            spotify_requests: self.scheduler.requests(),
+            spotify_requests_in_flight: self.scheduler.in_flight(),
+            rate_limit_tokens: self.scheduler.available(),
+            history_entries: self.history.count(),
+            stream_clients: self.stream_clients.count(),
+            idle_backoff_level: self.idle_backoff.level(),
+        }
+    }
+
+    /// Renders the metrics in the prometheus text format
+    pub fn render(&self) -> String {
+        let mut output = String::new();
+        gauge(
+            &mut output,
+            "spot_rate_limit_tokens",
+            "Tokens left in the Spotify request bucket",
+            self.scheduler.available(),
+        );
+        counter(
+            &mut output,
+            "spot_spotify_requests_total",
+            "Spotify requests made since startup",
+            self.scheduler.requests(),
+        );
+        gauge(
+            &mut output,
+            "spot_spotify_requests_in_flight",
+            "Spotify requests currently in flight",
+            self.scheduler.in_flight() as f64,
+        );
+        gauge(
+            &mut output,
+            "spot_history_entries",
+            "Plays retained in the in memory history",
+            self.history.count() as f64,
+        );
+        gauge(
+            &mut output,
+            "spot_stream_clients",
+            "Open /stream and /ws connections",
+            self.stream_clients.count() as f64,
+        );
+        gauge(
+            &mut output,
+            "spot_idle_backoff_level",
+            "Polls in a row that found nothing playing, each doubles how long that's cached",
+            self.idle_backoff.level() as f64,
+        );
+        output
+    }
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct MetricsSnapshot {
+    spotify_requests: u64,
+    spotify_requests_in_flight: usize,
+    rate_limit_tokens: f64,
+    history_entries: usize,
+    stream_clients: usize,
+    idle_backoff_level: u32,
+}
+
+fn counter(output: &mut String, name: &str, help: &str, value: u64) {
+    let _ = writeln!(output, "# HELP {name} {help}");
+    let _ = writeln!(output, "# TYPE {name} counter");
+    let _ = writeln!(output, "{name} {value}");
+}
+
+fn gauge(output: &mut String, name: &str, help: &str, value: f64) {
+    let _ = writeln!(output, "# HELP {name} {help}");
+    let _ = writeln!(output, "# TYPE {name} gauge");
+    let _ = writeln!(output, "{name} {value}");
+}
@@ -0,0 +1,265 @@
+//! Color palette of album art, a set of swatches like Android's Palette API so
+//! frontends can theme themselves with more than a single color.
+
+use std::io::Cursor;
+
+use image::io::{Limits, Reader};
+use serde::Serialize;
+use tracing::error;
+
+use crate::download;
+
+/// Most bytes of art downloaded, Spotify's is a fraction of that
+pub const MAX_ART_BYTES: usize = 2 * 1024 * 1024;
+
+// Art is downsampled before clustering, the palette barely changes but the work does
+const SAMPLE_SIZE: u32 = 48;
+const CLUSTERS: usize = 6;
+const ITERATIONS: usize = 10;
+const VIBRANT_MIN_SATURATION: f32 = 0.35;
+const MUTED_MAX_SATURATION: f32 = 0.4;
+// Art claiming to be larger is rejected before its pixels are allocated
+const MAX_DIMENSION: u32 = 4096;
+
+/// Without any swatches for art that can't be read, frontends fall back to
+/// their own colors
+#[derive(Serialize, Debug, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct Palette {
+    pub vibrant: Option<Swatch>,
+    pub muted: Option<Swatch>,
+    pub dark: Option<Swatch>,
+    pub light: Option<Swatch>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Swatch {
+    /// `#rrggbb`
+    pub color: String,
+    /// Share of the art's pixels closest to this color, `0` to `1`
+    pub population: f32,
+}
+
+struct Cluster {
+    color: [f32; 3],
+    pixels: usize,
+    saturation: f32,
+    lightness: f32,
+}
+
+/// Downloads the art and extracts its palette on the blocking pool, decoding
+/// would stall the runtime otherwise. Art that's too large or broken won't be
+/// any different next time, so it gets an empty palette to cache like any other.
+pub async fn fetch(image_url: &str) -> Result<Palette, ()> {
+    // Art is served from Spotify's CDN, which isn't rate limited like the api
+    let res = download::client().get(image_url).send().await;
+    let response = match res {
+        Ok(response) if response.status().is_success() => response,
+        _ => {
+            error!(?res, "Could not get album art");
+            return Err(());
+        }
+    };
+
+    let bytes = match download::read_capped(response, MAX_ART_BYTES).await {
+        Ok(Some(bytes)) => bytes,
+        Ok(None) => {
+            error!(image_url, "Album art too large");
+            return Ok(Palette::default());
+        }
+        Err(err) => {
+            error!(%err, "Could not read album art");
+            return Err(());
+        }
+    };
+
+    let palette = tokio::task::spawn_blocking(move || extract(&bytes))
+        .await
+        .map_err(|err| error!(%err, "Palette extraction panicked"))?;
+    Ok(palette.unwrap_or_else(|err| {
+        error!(%err, image_url, "Could not decode album art");
+        Palette::default()
+    }))
+}
+
+/// Clusters the pixels of the encoded art with k-means, then picks the swatches
+/// from the clusters by saturation and lightness
+pub fn extract(bytes: &[u8]) -> Result<Palette, image::ImageError> {
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(MAX_DIMENSION);
+    limits.max_image_height = Some(MAX_DIMENSION);
+    let mut reader = Reader::new(Cursor::new(bytes)).with_guessed_format()?;
+    reader.limits(limits);
+
+    let pixels = reader
+        .decode()?
+        .thumbnail(SAMPLE_SIZE, SAMPLE_SIZE)
+        .to_rgb8()
+        .pixels()
+        .map(|pixel| pixel.0.map(f32::from))
+        .collect::<Vec<_>>();
+
+    let clusters = k_means(&pixels)
+        .into_iter()
+        .filter(|(_, pixels)| *pixels > 0)
+        .map(|(color, pixels)| {
+            let (saturation, lightness) = saturation_lightness(color);
+            Cluster {
+                color,
+                pixels,
+                saturation,
+                lightness,
+            }
+        })
+        .collect::<Vec<_>>();
+
+    let swatch = |cluster: &Cluster| Swatch {
+        color: format!(
+            "#{:02x}{:02x}{:02x}",
+            cluster.color[0].round() as u8,
+            cluster.color[1].round() as u8,
+            cluster.color[2].round() as u8
+        ),
+        population: cluster.pixels as f32 / pixels.len() as f32,
+    };
+    let mid_tones = || {
+        clusters
+            .iter()
+            .filter(|cluster| (0.25..=0.75).contains(&cluster.lightness))
+    };
+
+    Ok(Palette {
+        vibrant: mid_tones()
+            .filter(|cluster| cluster.saturation >= VIBRANT_MIN_SATURATION)
+            .max_by(|a, b| a.saturation.total_cmp(&b.saturation))
+            .map(swatch),
+        muted: mid_tones()
+            .filter(|cluster| cluster.saturation <= MUTED_MAX_SATURATION)
+            .min_by(|a, b| a.saturation.total_cmp(&b.saturation))
+            .map(swatch),
+        dark: clusters
+            .iter()
+            .min_by(|a, b| a.lightness.total_cmp(&b.lightness))
+            .map(swatch),
+        light: clusters
+            .iter()
+            .max_by(|a, b| a.lightness.total_cmp(&b.lightness))
+            .map(swatch),
+    })
+}
+
+/// Centroids with the number of pixels closest to them. Seeded with pixels
+/// spread evenly by brightness so the same art always gives the same palette.
+fn k_means(pixels: &[[f32; 3]]) -> Vec<([f32; 3], usize)> {
+    if pixels.is_empty() {
+        return Vec::new();
+    }
+
+    let mut by_brightness = pixels.to_vec();
+    by_brightness.sort_by(|a, b| a.iter().sum::<f32>().total_cmp(&b.iter().sum()));
+    let mut centroids = (0..CLUSTERS)
+        .map(|cluster| by_brightness[cluster * (pixels.len() - 1) / (CLUSTERS - 1)])
+        .collect::<Vec<_>>();
+
+    let mut assigned = vec![0; pixels.len()];
+    for _ in 0..ITERATIONS {
+        for (pixel, cluster) in pixels.iter().zip(assigned.iter_mut()) {
+            *cluster = nearest(&centroids, pixel);
+        }
+
+        let mut sums = vec![([0.0; 3], 0); centroids.len()];
+        for (pixel, cluster) in pixels.iter().zip(&assigned) {
+            let (sum, count) = &mut sums[*cluster];
+            for channel in 0..3 {
+                sum[channel] += pixel[channel];
+            }
+            *count += 1;
+        }
+
+        // Clusters left without pixels keep their centroid
+        for (centroid, (sum, count)) in centroids.iter_mut().zip(&sums) {
+            if *count > 0 {
+                *centroid = sum.map(|channel| channel / *count as f32);
+            }
+        }
+    }
+
+    let mut counts = vec![0; centroids.len()];
+    for pixel in pixels {
+        counts[nearest(&centroids, pixel)] += 1;
+    }
+    centroids.into_iter().zip(counts).collect()
+}
+
+fn nearest(centroids: &[[f32; 3]], pixel: &[f32; 3]) -> usize {
+    let distance = |centroid: &[f32; 3]| {
+        (0..3)
+            .map(|channel| (centroid[channel] - pixel[channel]).powi(2))
+            .sum::<f32>()
+    };
+    centroids
+        .iter()
+        .enumerate()
+        .min_by(|(_, a), (_, b)| distance(a).total_cmp(&distance(b)))
+        .map(|(index, _)| index)
+        .unwrap_or_default()
+}
+
+/// HSL saturation and lightness, `0` to `1`
+fn saturation_lightness(color: [f32; 3]) -> (f32, f32) {
+    let [red, green, blue] = color.map(|channel| channel / 255.0);
+    let max = red.max(green).max(blue);
+    let min = red.min(green).min(blue);
+    let lightness = (max + min) / 2.0;
+    if max == min {
+        return (0.0, lightness);
+    }
+
+    let saturation = (max - min) / (1.0 - (2.0 * lightness - 1.0).abs());
+    (saturation, lightness)
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{DynamicImage, ImageOutputFormat, Rgb, RgbImage};
+
+    use super::*;
+
+    /// PNG of the given size, its left half red and right half dark blue
+    fn png(width: u32, height: u32) -> Vec<u8> {
+        let art = RgbImage::from_fn(width, height, |x, _| {
+            if x < width / 2 {
+                Rgb([220, 30, 30])
+            } else {
+                Rgb([20, 20, 60])
+            }
+        });
+        let mut bytes = Vec::new();
+        DynamicImage::ImageRgb8(art)
+            .write_to(&mut Cursor::new(&mut bytes), ImageOutputFormat::Png)
+            .unwrap();
+        bytes
+    }
+
+    #[test]
+    fn swatches_are_picked_from_the_art() {
+        let palette = extract(&png(64, 64)).unwrap();
+
+        assert_eq!(palette.vibrant.unwrap().color, "#dc1e1e");
+        assert!(palette.dark.is_some());
+    }
+
+    #[test]
+    fn art_larger_than_the_limit_is_rejected_before_decoding() {
+        assert!(extract(&png(MAX_DIMENSION + 1, 1)).is_err());
+    }
+
+    #[test]
+    fn truncated_art_is_rejected() {
+        let bytes = png(64, 64);
+
+        assert!(extract(&bytes[..bytes.len() / 2]).is_err());
+        assert!(extract(b"not an image").is_err());
+    }
+}
@@ -0,0 +1,160 @@
+//! Query params of the player and listening history endpoints, validated in one
+//! place so the handlers only deal with valid options.
+
+use serde::Deserialize;
+
+use crate::spotify::PlayedCursor;
+
+#[derive(Deserialize, Debug)]
+pub struct SeekQuery {
+    pub position_ms: Option<i64>,
+    pub percent: Option<f64>,
+    pub device_id: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SeekTarget {
+    Position(i64),
+    Percent(f64),
+}
+
+impl SeekQuery {
+    /// Exactly one of `position_ms` and `percent` has to be set and in range
+    pub fn target(&self) -> Option<SeekTarget> {
+        match (self.position_ms, self.percent) {
+            (Some(position_ms), None) if position_ms >= 0 => {
+                Some(SeekTarget::Position(position_ms))
+            }
+            (None, Some(percent)) if (0.0..=100.0).contains(&percent) => {
+                Some(SeekTarget::Percent(percent))
+            }
+            _ => None,
+        }
+    }
+}
+
+impl SeekTarget {
+    /// Percentages can't be resolved without the duration of the current song
+    pub fn position_ms(&self, duration_ms: Option<i64>) -> Option<i64> {
+        match self {
+            SeekTarget::Position(position_ms) => Some(*position_ms),
+            SeekTarget::Percent(percent) => {
+                duration_ms.map(|duration_ms| (duration_ms as f64 * percent / 100.0) as i64)
+            }
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct PlayOnDeviceQuery {
+    pub device_name: String,
+    pub uri: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct PlayQuery {
+    pub uri: Option<String>,
+    pub position_ms: Option<i64>,
+    pub shuffle: Option<bool>,
+    pub device_id: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct RecentlyPlayedQuery {
+    pub limit: Option<usize>,
+    pub before: Option<i64>,
+    pub after: Option<i64>,
+}
+
+// Spotify answers at most 50 plays per page
+const MAX_RECENTLY_PLAYED_LIMIT: usize = 50;
+
+impl RecentlyPlayedQuery {
+    /// Defaults to 20, `Err` outside of the `1` to `50` Spotify accepts
+    pub fn limit(&self) -> Result<usize, ()> {
+        match self.limit.unwrap_or(20) {
+            limit @ 1..=MAX_RECENTLY_PLAYED_LIMIT => Ok(limit),
+            _ => Err(()),
+        }
+    }
+
+    /// At most one of `before` and `after` can be set, `Err` for both
+    pub fn cursor(&self) -> Result<Option<PlayedCursor>, ()> {
+        match (self.before, self.after) {
+            (Some(_), Some(_)) => Err(()),
+            (Some(before), None) => Ok(Some(PlayedCursor::Before(before))),
+            (None, Some(after)) => Ok(Some(PlayedCursor::After(after))),
+            (None, None) => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{extract::Query, http::Uri};
+
+    use super::*;
+
+    fn seek(query: &str) -> SeekQuery {
+        let uri: Uri = format!("/player/seek?{:}", query).parse().unwrap();
+        Query::try_from_uri(&uri).unwrap().0
+    }
+
+    fn recently_played(query: &str) -> RecentlyPlayedQuery {
+        let uri: Uri = format!("/recently-played?{:}", query).parse().unwrap();
+        Query::try_from_uri(&uri).unwrap().0
+    }
+
+    #[test]
+    fn seek_takes_either_a_position_or_a_percent() {
+        assert_eq!(
+            seek("position_ms=30000").target(),
+            Some(SeekTarget::Position(30000))
+        );
+        assert_eq!(seek("percent=50").target(), Some(SeekTarget::Percent(50.0)));
+    }
+
+    #[test]
+    fn seek_rejects_both_or_neither() {
+        assert_eq!(seek("position_ms=30000&percent=50").target(), None);
+        assert_eq!(seek("").target(), None);
+        assert_eq!(seek("device_id=abc").target(), None);
+    }
+
+    #[test]
+    fn seek_rejects_out_of_range_values() {
+        assert_eq!(seek("position_ms=-1").target(), None);
+        assert_eq!(seek("percent=100.5").target(), None);
+        assert_eq!(seek("percent=-1").target(), None);
+    }
+
+    #[test]
+    fn percent_needs_the_duration() {
+        let target = SeekTarget::Percent(25.0);
+        assert_eq!(target.position_ms(Some(200_000)), Some(50_000));
+        assert_eq!(target.position_ms(None), None);
+        assert_eq!(SeekTarget::Position(10).position_ms(None), Some(10));
+    }
+
+    #[test]
+    fn recently_played_limit_is_between_1_and_50() {
+        assert_eq!(recently_played("").limit(), Ok(20));
+        assert_eq!(recently_played("limit=50").limit(), Ok(50));
+        assert_eq!(recently_played("limit=51").limit(), Err(()));
+        assert_eq!(recently_played("limit=0").limit(), Err(()));
+    }
+
+    #[test]
+    fn recently_played_takes_at_most_one_cursor() {
+        assert!(matches!(recently_played("").cursor(), Ok(None)));
+        assert!(matches!(
+            recently_played("before=10").cursor(),
+            Ok(Some(PlayedCursor::Before(10)))
+        ));
+        assert!(matches!(
+            recently_played("after=10").cursor(),
+            Ok(Some(PlayedCursor::After(10)))
+        ));
+        assert!(recently_played("before=10&after=10").cursor().is_err());
+    }
+}
@@ -0,0 +1,174 @@
+use axum::{
+    http::{header, HeaderValue},
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Case {
+    #[default]
+    Camel,
+    Snake,
+}
+
+/// Query options every JSON endpoint returning Spotify data accepts
+#[derive(Deserialize, Debug, Default)]
+pub struct ResponseOptions {
+    #[serde(default)]
+    pub case: Case,
+    /// Indents the JSON for reading it with curl, compact otherwise
+    #[serde(default)]
+    pub pretty: bool,
+}
+
+pub fn json<T: Serialize>(options: &ResponseOptions, value: &T) -> Response {
+    if options.case == Case::Camel {
+        return serialize(options, value);
+    }
+
+    match serde_json::to_value(value) {
+        Ok(value) => serialize(options, &to_snake_case(value)),
+        Err(_) => serialize(options, value),
+    }
+}
+
+fn serialize<T: Serialize>(options: &ResponseOptions, value: &T) -> Response {
+    if !options.pretty {
+        return Json(value).into_response();
+    }
+
+    match serde_json::to_vec_pretty(value) {
+        Ok(bytes) => (
+            [(
+                header::CONTENT_TYPE,
+                HeaderValue::from_static("application/json"),
+            )],
+            bytes,
+        )
+            .into_response(),
+        // Answered with the same error as the compact serializer would
+        Err(_) => Json(value).into_response(),
+    }
+}
+
+/// Rewrites the camelCase keys the models serialize with to snake_case
+pub fn to_snake_case(value: Value) -> Value {
+    match value {
+        Value::Object(object) => Value::Object(
+            object
+                .into_iter()
+                .map(|(key, value)| (snake_case_key(&key), to_snake_case(value)))
+                .collect(),
+        ),
+        Value::Array(values) => Value::Array(values.into_iter().map(to_snake_case).collect()),
+        value => value,
+    }
+}
+
+fn snake_case_key(key: &str) -> String {
+    let mut snake_case = String::with_capacity(key.len());
+    for character in key.chars() {
+        if character.is_ascii_uppercase() {
+            snake_case.push('_');
+            snake_case.push(character.to_ascii_lowercase());
+        } else {
+            snake_case.push(character);
+        }
+    }
+
+    snake_case
+}
+
+/// Prunes `value` down to the comma separated, dot delimited `fields`, arrays
+/// apply the rest of a path to each of their items. Paths matching nothing are ignored,
+/// null, like nothing playing, is returned as is
+pub fn project(value: &Value, fields: &str) -> Value {
+    if value.is_null() {
+        return Value::Null;
+    }
+
+    fields
+        .split(',')
+        .filter(|field| !field.is_empty())
+        .filter_map(|field| pick(value, &field.split('.').collect::<Vec<_>>()))
+        .fold(Value::Object(Default::default()), merge)
+}
+
+fn pick(value: &Value, path: &[&str]) -> Option<Value> {
+    let Some((key, rest)) = path.split_first() else {
+        return Some(value.clone());
+    };
+
+    match value {
+        Value::Object(object) => {
+            let picked = pick(object.get(*key)?, rest)?;
+            Some(Value::Object(
+                [(key.to_string(), picked)].into_iter().collect(),
+            ))
+        }
+        Value::Array(values) => Some(Value::Array(
+            values
+                .iter()
+                .map(|value| pick(value, path).unwrap_or(Value::Object(Default::default())))
+                .collect(),
+        )),
+        _ => None,
+    }
+}
+
+fn merge(into: Value, from: Value) -> Value {
+    match (into, from) {
+        (Value::Object(mut into), Value::Object(from)) => {
+            for (key, value) in from {
+                let merged = match into.remove(&key) {
+                    Some(existing) => merge(existing, value),
+                    None => value,
+                };
+                into.insert(key, merged);
+            }
+            Value::Object(into)
+        }
+        (Value::Array(into), Value::Array(from)) => Value::Array(
+            into.into_iter()
+                .zip(from)
+                .map(|(into, from)| merge(into, from))
+                .collect(),
+        ),
+        (_, from) => from,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn project_keeps_null_as_null() {
+        assert_eq!(project(&Value::Null, "item.name"), Value::Null);
+    }
+
+    #[test]
+    fn project_picks_paths_through_arrays() {
+        let song = json!({
+            "isPlaying": true,
+            "item": { "name": "Song", "artists": [{ "name": "Artist", "id": "1" }] }
+        });
+
+        assert_eq!(
+            project(&song, "isPlaying,item.artists.name"),
+            json!({ "isPlaying": true, "item": { "artists": [{ "name": "Artist" }] } })
+        );
+    }
+
+    #[test]
+    fn project_matches_snake_case_fields_after_conversion() {
+        let song = to_snake_case(json!({ "isPlaying": true, "progressMs": 10 }));
+
+        assert_eq!(project(&song, "is_playing"), json!({ "is_playing": true }));
+    }
+}
@@ -0,0 +1,100 @@
+use std::{
+    cell::Cell,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tracing::debug;
+
+// Spotify enforces its limit over a rolling 30 second window, stay well below it
+const BUCKET_CAPACITY: f64 = 30.0;
+const REFILL_PER_SECOND: f64 = 3.0;
+
+tokio::task_local! {
+    /// Spotify requests made while serving the current request, only set for
+    /// requests asking for it as counting every request isn't worth it
+    pub static SPOTIFY_CALLS: Cell<u64>;
+}
+
+/// Token bucket every outbound Spotify request has to take a token from.
+/// When the bucket is empty requests are queued until a token is refilled
+/// rather than being dropped. Requests in flight at once are capped as well,
+/// so a burst can't open more connections than `max_concurrent`.
+#[derive(Clone)]
+pub struct Scheduler {
+    bucket: Arc<Mutex<Bucket>>,
+    in_flight: Arc<Semaphore>,
+    max_concurrent: usize,
+    requests: Arc<AtomicU64>,
+}
+
+struct Bucket {
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl Bucket {
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.refilled_at).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * REFILL_PER_SECOND).min(BUCKET_CAPACITY);
+        self.refilled_at = now;
+    }
+}
+
+impl Scheduler {
+    pub fn new(max_concurrent: usize) -> Self {
+        Self {
+            bucket: Arc::new(Mutex::new(Bucket {
+                tokens: BUCKET_CAPACITY,
+                refilled_at: Instant::now(),
+            })),
+            in_flight: Arc::new(Semaphore::new(max_concurrent)),
+            max_concurrent,
+            requests: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// Waits for a slot and a token, the request counts as in flight until the
+    /// returned permit is dropped
+    pub async fn acquire(&self) -> OwnedSemaphorePermit {
+        let _ = SPOTIFY_CALLS.try_with(|calls| calls.set(calls.get() + 1));
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        // The semaphore is never closed
+        let permit = self.in_flight.clone().acquire_owned().await.unwrap();
+        loop {
+            let wait = {
+                let mut bucket = self.bucket.lock().unwrap();
+                bucket.refill();
+                if bucket.tokens >= 1.0 {
+                    bucket.tokens -= 1.0;
+                    return permit;
+                }
+
+                (1.0 - bucket.tokens) / REFILL_PER_SECOND
+            };
+
+            debug!(wait, "Spotify request bucket is empty, queueing request");
+            tokio::time::sleep(Duration::from_secs_f64(wait)).await;
+        }
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.max_concurrent - self.in_flight.available_permits()
+    }
+
+    /// Spotify requests made since startup
+    pub fn requests(&self) -> u64 {
+        self.requests.load(Ordering::Relaxed)
+    }
+
+    pub fn available(&self) -> f64 {
+        let mut bucket = self.bucket.lock().unwrap();
+        bucket.refill();
+        bucket.tokens
+    }
+}
@@ -1,45 +1,300 @@
-use axum::body;
-use serde::{Deserialize, Serialize};
-use strum_macros::Display;
-use tracing::{error, info, instrument};
+use std::{
+    cell::RefCell,
+    collections::{HashMap, HashSet},
+    sync::OnceLock,
+};
+
+use chrono_tz::Tz;
+use serde::{de::DeserializeOwned, ser::SerializeStruct, Deserialize, Serialize};
+use serde_json::Value;
+use strum_macros::{Display, EnumString};
+use tracing::{debug, error, info, instrument};
+
+use crate::{
+    backoff::IdleBackoff,
+    cache::{self, LruCache},
+    config::Config,
+    download,
+    history::{History, HistoryEntry},
+    palette::Palette,
+    response::to_snake_case,
+    scheduler::Scheduler,
+};
 
 const TEN_SECONDS: i64 = 10000;
 const TEN_MINUTES: i64 = TEN_SECONDS * 60;
+const ONE_DAY: i64 = TEN_MINUTES * 6 * 24;
+const ARTISTS_CACHE_CAPACITY: usize = 500;
+const NEW_RELEASES_CACHE_CAPACITY: usize = 16;
+const CANVAS_CACHE_CAPACITY: usize = 100;
+const SYNCED_LYRICS_CACHE_CAPACITY: usize = 100;
+const PREVIEW_URLS_CACHE_CAPACITY: usize = 100;
+const PALETTES_CACHE_CAPACITY: usize = 100;
+const RECENTLY_PLAYED_CACHE_CAPACITY: usize = 8;
+const LIKED_TRACKS_CACHE_CAPACITY: usize = 16;
+const LIKED_IDS_CACHE_CAPACITY: usize = 500;
+const AUDIO_ANALYSES_CACHE_CAPACITY: usize = 50;
+const AUDIO_FEATURES_CACHE_CAPACITY: usize = 200;
+const PLAYLIST_TRACKS_CACHE_CAPACITY: usize = 100;
+// Playlists whose tracks are fetched per request, the rest wait for the next one
+const PLAYLISTS_SCANNED_PER_REQUEST: usize = 5;
+// Most tracks Spotify returns audio features for at once
+const AUDIO_FEATURES_BATCH_SIZE: usize = 100;
+const SPOTIFY_API_URL: &str = "https://api.spotify.com/v1";
+const SPOTIFY_ACCOUNTS_URL: &str = "https://accounts.spotify.com";
+const MAX_PAGE_SIZE: usize = 50;
+const MIN_CURRENT_SONG_TTL: i64 = 2000;
+// Spotify takes a moment to report the next track once the current one ended
+const TRACK_CHANGE_GRACE: i64 = 500;
+const ACTIVE_DEVICE_TTL: i64 = TEN_SECONDS / 2;
+const TOKEN_EXPIRY_MARGIN: i64 = 60;
+// Spotify's access tokens last an hour
+const TOKEN_LIFETIME: i64 = 60 * 60;
+const TOKEN_REFRESH_BACKOFF: std::time::Duration = std::time::Duration::from_millis(250);
+// Lyrics of even the longest tracks are a few kilobytes
+const MAX_SYNCED_LYRICS_BYTES: usize = 1024 * 1024;
+const PLAY_FROM_SEEK_ATTEMPTS: u32 = 3;
+const PLAY_FROM_SEEK_DELAY: std::time::Duration = std::time::Duration::from_millis(300);
+const DEVICE_WAKE_DELAY: std::time::Duration = std::time::Duration::from_millis(500);
+
+tokio::task_local! {
+    /// Error Spotify answered the last failed request made while serving the
+    /// current request with, only set for requests asking for it
+    pub static SPOTIFY_ERROR: RefCell<Option<SpotifyError>>;
+}
+
+/// Scopes used by some endpoint, missing ones fail those endpoints with a 403
+const USED_SCOPES: [&str; 11] = [
+    "user-read-currently-playing",
+    "user-read-playback-state",
+    "user-read-playback-position",
+    "user-modify-playback-state",
+    "user-read-private",
+    "user-top-read",
+    "user-follow-read",
+    "user-library-read",
+    "user-read-recently-played",
+    "playlist-read-private",
+    "playlist-modify-public",
+];
+
+/// Scope each route fails without, by the path it was routed with
+const ROUTE_SCOPES: [(&str, &str); 25] = [
+    ("/", "user-read-currently-playing"),
+    ("/top-songs", "user-top-read"),
+    ("/top-songs/combined", "user-top-read"),
+    ("/top-songs/compare", "user-top-read"),
+    ("/stats/taste", "user-top-read"),
+    ("/current/in-playlists", "playlist-read-private"),
+    ("/next-up", "user-read-playback-state"),
+    ("/devices", "user-read-playback-state"),
+    ("/player", "user-read-playback-state"),
+    ("/following", "user-follow-read"),
+    ("/saved-albums", "user-library-read"),
+    ("/saved-shows", "user-library-read"),
+    ("/liked", "user-library-read"),
+    ("/liked/contains", "user-library-read"),
+    ("/recently-played", "user-read-recently-played"),
+    ("/playlists/search", "playlist-read-private"),
+    ("/player/:player_state", "user-modify-playback-state"),
+    ("/player/play", "user-modify-playback-state"),
+    ("/player/seek", "user-modify-playback-state"),
+    ("/player/play-on", "user-modify-playback-state"),
+    ("/player/play-liked", "user-modify-playback-state"),
+    ("/player/repeat-cycle", "user-modify-playback-state"),
+    ("/player/add-recommendations", "user-modify-playback-state"),
+    ("/playlist/:playlist_id/tracks", "playlist-modify-public"),
+    ("/playlist/:playlist_id/reorder", "playlist-modify-public"),
+];
+
+/// Image substituted into albums without any art, set once from the config
+static FALLBACK_IMAGE_URL: OnceLock<String> = OnceLock::new();
+
+/// Path the routes are nested under, set once from the config
+static PATH_PREFIX: OnceLock<String> = OnceLock::new();
 
 pub struct Spot {
+    // Spotify's own, only pointed elsewhere by tests
+    api_url: String,
+    accounts_url: String,
     pub client_id: String,
     pub client_secret: String,
     pub token: String,
     pub refresh_token: String,
     pub auth_expires_at: i64,
+    // Granted with the current token, refreshed along with it
+    scopes: Vec<String>,
+    token_refresh_retries: u32,
+    device_wake_retries: u32,
     pub current_song_cached_response: Option<CurrentSong>,
     pub current_song_cached_till: i64,
     pub current_song_cached_at: i64,
-    pub top_songs_cached_response: Option<Vec<Item>>,
-    pub top_songs_cached_till: i64,
+    pub current_song_cached_types: String,
+    current_song_cached_nothing_playing: bool,
+    // Per time range, a `None` response caches an error
+    pub top_songs_cached_response: HashMap<TimeRange, Option<CachedTopSongs>>,
+    pub top_songs_cached_till: HashMap<TimeRange, i64>,
+    top_songs_fetch_limit: usize,
+    compress_cache: bool,
+    max_pages: usize,
+    artists_cache: LruCache<String, FullArtist>,
+    pub followed_artists_cached_response: Option<Vec<FullArtist>>,
+    pub followed_artists_cached_till: i64,
+    followed_artists_complete: bool,
+    refresh_active_device: bool,
+    pub active_device_cached_response: Option<String>,
+    pub active_device_cached_till: i64,
+    pub queue_cached_response: Option<Vec<PlayingItem>>,
+    pub queue_cached_till: i64,
+    pub saved_albums_cached_response: Option<Vec<SavedAlbum>>,
+    pub saved_albums_cached_till: i64,
+    saved_albums_complete: bool,
+    pub saved_shows_cached_response: Option<Vec<SavedShow>>,
+    pub saved_shows_cached_till: i64,
+    saved_shows_complete: bool,
+    pub playlists_cached_response: Option<Vec<Playlist>>,
+    pub playlists_cached_till: i64,
+    pub genre_seeds_cached_response: Option<Vec<String>>,
+    pub genre_seeds_cached_till: i64,
+    pub profile_cached_response: Option<Profile>,
+    pub profile_cached_till: i64,
+    // `&market=` param of the top songs, empty without a known country
+    market_cached_response: Option<String>,
+    market_cached_till: i64,
+    new_releases_cache: LruCache<String, Vec<Album>>,
+    canvas_provider_url: Option<String>,
+    canvas_cache: LruCache<String, Option<String>>,
+    synced_lyrics_provider_url: Option<String>,
+    synced_lyrics_cache: LruCache<String, Option<SyncedLyrics>>,
+    preview_urls_cache: LruCache<String, Option<String>>,
+    // Per image url
+    palettes_cache: LruCache<String, Palette>,
+    // Per limit, only the latest page without a cursor is cached
+    recently_played_cache: LruCache<usize, RecentlyPlayed>,
+    // Per offset and limit
+    liked_tracks_cache: LruCache<(usize, usize), Vec<LikedTrack>>,
+    // Per track id, whether it's liked
+    liked_ids_cache: LruCache<String, bool>,
+    // Per track id, an analysis never changes
+    audio_analyses_cache: LruCache<String, AudioAnalysis>,
+    // Per track id, `None` for tracks Spotify has no features for
+    audio_features_cache: LruCache<String, Option<AudioFeatures>>,
+    // Per playlist id and snapshot, the ids of the playlist's tracks
+    playlist_tracks_cache: LruCache<(String, String), HashSet<String>>,
+    pub featured_playlists_cached_response: Option<FeaturedPlaylists>,
+    pub featured_playlists_cached_till: i64,
+    pub health_cached_response: Option<SpotifyHealth>,
+    pub health_cached_till: i64,
+    scheduler: Scheduler,
+    history: History,
+    idle_backoff: IdleBackoff,
+    timezone: Tz,
 }
 
 impl Spot {
-    pub fn new(client_id: String, client_secret: String, refresh_token: String) -> Self {
+    pub fn new(
+        config: &Config,
+        scheduler: Scheduler,
+        history: History,
+        idle_backoff: IdleBackoff,
+    ) -> Self {
+        if let Some(url) = &config.fallback_image_url {
+            let _ = FALLBACK_IMAGE_URL.set(url.clone());
+        }
+        let _ = PATH_PREFIX.set(config.path_prefix.clone());
+
         Self {
-            client_id,
-            client_secret,
+            api_url: SPOTIFY_API_URL.into(),
+            accounts_url: SPOTIFY_ACCOUNTS_URL.into(),
+            client_id: config.spotify_client_id.clone(),
+            client_secret: config.spotify_client_secret.clone(),
             token: String::new(),
-            refresh_token,
+            refresh_token: config.spotify_refresh_token.clone(),
             auth_expires_at: 0,
+            scopes: Vec::new(),
+            token_refresh_retries: config.token_refresh_retries,
+            device_wake_retries: config.device_wake_retries,
             current_song_cached_response: None,
             current_song_cached_till: 0,
             current_song_cached_at: 0,
-            top_songs_cached_response: None,
-            top_songs_cached_till: 0,
+            current_song_cached_types: String::new(),
+            current_song_cached_nothing_playing: false,
+            top_songs_cached_response: HashMap::new(),
+            top_songs_cached_till: HashMap::new(),
+            top_songs_fetch_limit: config.top_songs_fetch_limit,
+            compress_cache: config.compress_cache,
+            max_pages: config.max_pages,
+            artists_cache: LruCache::new(ARTISTS_CACHE_CAPACITY, TEN_MINUTES * 6),
+            followed_artists_cached_response: None,
+            followed_artists_cached_till: 0,
+            followed_artists_complete: false,
+            refresh_active_device: config.refresh_active_device,
+            active_device_cached_response: None,
+            active_device_cached_till: 0,
+            queue_cached_response: None,
+            queue_cached_till: 0,
+            saved_albums_cached_response: None,
+            saved_albums_cached_till: 0,
+            saved_albums_complete: false,
+            saved_shows_cached_response: None,
+            saved_shows_cached_till: 0,
+            saved_shows_complete: false,
+            playlists_cached_response: None,
+            playlists_cached_till: 0,
+            genre_seeds_cached_response: None,
+            genre_seeds_cached_till: 0,
+            profile_cached_response: None,
+            profile_cached_till: 0,
+            market_cached_response: None,
+            market_cached_till: 0,
+            new_releases_cache: LruCache::new(NEW_RELEASES_CACHE_CAPACITY, TEN_MINUTES * 3),
+            canvas_provider_url: config.canvas_provider_url.clone(),
+            canvas_cache: LruCache::new(CANVAS_CACHE_CAPACITY, ONE_DAY),
+            synced_lyrics_provider_url: config.synced_lyrics_provider_url.clone(),
+            synced_lyrics_cache: LruCache::new(SYNCED_LYRICS_CACHE_CAPACITY, ONE_DAY),
+            preview_urls_cache: LruCache::new(PREVIEW_URLS_CACHE_CAPACITY, ONE_DAY),
+            palettes_cache: LruCache::new(PALETTES_CACHE_CAPACITY, ONE_DAY),
+            recently_played_cache: LruCache::new(RECENTLY_PLAYED_CACHE_CAPACITY, TEN_SECONDS * 3),
+            liked_tracks_cache: LruCache::new(LIKED_TRACKS_CACHE_CAPACITY, TEN_SECONDS * 6),
+            liked_ids_cache: LruCache::new(LIKED_IDS_CACHE_CAPACITY, TEN_SECONDS * 6),
+            audio_analyses_cache: LruCache::new(AUDIO_ANALYSES_CACHE_CAPACITY, ONE_DAY),
+            audio_features_cache: LruCache::new(AUDIO_FEATURES_CACHE_CAPACITY, ONE_DAY),
+            // A changed playlist gets a new snapshot id, so a stale entry is never read
+            playlist_tracks_cache: LruCache::new(PLAYLIST_TRACKS_CACHE_CAPACITY, ONE_DAY),
+            featured_playlists_cached_response: None,
+            featured_playlists_cached_till: 0,
+            health_cached_response: None,
+            health_cached_till: 0,
+            scheduler,
+            history,
+            idle_backoff,
+            timezone: config.timezone,
         }
     }
 
     #[instrument(skip(self))]
-    pub async fn get_token(&mut self) -> Result<(), ()> {
+    pub async fn get_token(&mut self) -> Result<(), SpotError> {
+        let mut backoff = TOKEN_REFRESH_BACKOFF;
+        let mut retries = 0;
+        loop {
+            match self.refresh_token().await {
+                // Only transient failures are retried, a revoked refresh token stays revoked
+                Err(SpotError::Unknown) if retries < self.token_refresh_retries => {
+                    retries += 1;
+                    info!(retries, ?backoff, "Retrying token refresh");
+                    tokio::time::sleep(backoff).await;
+                    backoff *= 2;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    async fn refresh_token(&mut self) -> Result<(), SpotError> {
         let client = reqwest::Client::new();
+        let _permit = self.scheduler.acquire().await;
         let res = client
-            .post("https://accounts.spotify.com/api/token")
+            .post(format!("{:}/api/token", self.accounts_url))
             .basic_auth(&self.client_id, Some(&self.client_secret))
             .header("Content-Type", "application/x-www-form-urlencoded")
             .form(&[
@@ -51,61 +306,120 @@ impl Spot {
 
         if let Err(error) = &res {
             error!(%error, "Could not get users token");
-            return Err(());
+            return Err(SpotError::Unknown);
         }
 
         let response = res.unwrap();
-        if !response.status().is_success() {
-            error!(rfresh = self.refresh_token, "Could not get users token");
-            return Err(());
-        }
-
+        let status = response.status();
         let body = response.text().await;
         if let Err(err) = &body {
             error!(%err, "Could not decode spotify body");
-            return Err(());
+            return Err(SpotError::Unknown);
+        }
+
+        let body = body.unwrap();
+        if !status.is_success() {
+            let auth_error: Result<AuthErrorResponse, serde_json::Error> =
+                serde_json::from_str(&body);
+            if matches!(&auth_error, Ok(auth_error) if auth_error.error == "invalid_grant") {
+                error!(
+                    "Spotify refresh token was revoked, re-authenticate and update SPOTIFY_REFRESH_TOKEN"
+                );
+                return Err(SpotError::RefreshTokenRevoked);
+            }
+
+            error!(%status, body, "Could not get users token");
+            record_spotify_error(status, &body);
+            return Err(SpotError::Unknown);
         }
 
-        let json = serde_json::from_str(&body.unwrap());
+        let json = serde_json::from_str(&body);
 
         if let Err(err) = &json {
             error!(%err, "Could not parse spotify response to json");
-            return Err(());
+            return Err(SpotError::Unknown);
         }
 
         let json: AuthResponse = json.unwrap();
         self.token = json.access_token;
-        self.auth_expires_at = json.expires_in + chrono::Utc::now().timestamp();
+        // Refreshed a little early, a skewed clock or a slow response would
+        // otherwise keep using the token past its real expiry
+        self.auth_expires_at =
+            json.expires_in - TOKEN_EXPIRY_MARGIN + chrono::Utc::now().timestamp();
+        self.scopes = json.scope.split_whitespace().map(String::from).collect();
 
         info!("Updated spotify token");
         Ok(())
     }
 
     #[instrument(skip(self))]
-    pub async fn get_current_song(&mut self) -> Result<Option<CurrentSong>, ()> {
+    pub async fn get_current_song(
+        &mut self,
+        additional_types: &[AdditionalType],
+    ) -> Result<Option<CurrentSong>, ()> {
+        let additional_types = additional_types
+            .iter()
+            .map(|additional_type| additional_type.to_string())
+            .collect::<Vec<String>>()
+            .join(",");
+
+        if additional_types != self.current_song_cached_types {
+            // The cache only holds a response for one set of types
+            debug!(
+                cached_types = self.current_song_cached_types,
+                "Current song cache holds different types, dropping it"
+            );
+            self.current_song_cached_response = None;
+            self.current_song_cached_till = 0;
+        }
+
         if chrono::Utc::now().timestamp_millis() < self.current_song_cached_till
             && self.current_song_cached_response.is_some()
         {
             let mut current_song = self.current_song_cached_response.clone().unwrap();
-            current_song.progress_ms +=
-                chrono::Utc::now().timestamp_millis() - self.current_song_cached_at;
+            // Paused songs keep their progress, only a playing one moved on since
+            let elapsed_ms = if current_song.is_playing {
+                chrono::Utc::now().timestamp_millis() - self.current_song_cached_at
+            } else {
+                0
+            };
+            current_song.set_progress_ms(current_song.progress_ms + elapsed_ms);
 
+            debug!(
+                elapsed_ms,
+                ttl_ms = self.current_song_cached_till - chrono::Utc::now().timestamp_millis(),
+                "Current song cache hit, extrapolated progress"
+            );
             return Ok(Some(current_song));
+        } else if chrono::Utc::now().timestamp_millis() < self.current_song_cached_till
+            && self.current_song_cached_nothing_playing
+        {
+            debug!(
+                ttl_ms = self.current_song_cached_till - chrono::Utc::now().timestamp_millis(),
+                "Current song cache hit, nothing playing"
+            );
+            return Ok(None);
         } else if chrono::Utc::now().timestamp_millis() < self.current_song_cached_till {
+            debug!(
+                ttl_ms = self.current_song_cached_till - chrono::Utc::now().timestamp_millis(),
+                "Current song cache holds an error"
+            );
             return Err(());
         }
 
-        if chrono::Utc::now().timestamp() > self.auth_expires_at {
-            if let Err(_) = self.get_token().await {
-                return Err(());
-            }
+        debug!("Current song cache miss");
+
+        if chrono::Utc::now().timestamp() > self.auth_expires_at && self.get_token().await.is_err()
+        {
+            return Err(());
         }
 
         let client = reqwest::Client::new();
-        let res = client
-            .get("https://api.spotify.com/v1/me/player/currently-playing")
-            .header("authorization", format!("Bearer {:}", self.token))
-            .send()
+        let res = self
+            .send(client.get(format!(
+                "{:}/me/player/currently-playing?additional_types={:}",
+                self.api_url, additional_types
+            )))
             .await;
 
         let mut errored = false;
@@ -115,13 +429,23 @@ impl Spot {
         }
 
         let response = res.unwrap();
-        if !response.status().is_success() {
+        let status = response.status();
+        if !status.is_success() {
             error!(?response, "Could not get current song");
             errored = true;
         }
 
         if response.status() == 204 {
-            // No song playing
+            // No song playing, cached as well so concurrent requests waiting on the
+            // state lock don't each ask Spotify again. The longer nothing plays
+            // the longer it's cached.
+            let ttl_ms = self.idle_backoff.increase();
+            self.current_song_cached_types = additional_types;
+            self.current_song_cached_response = None;
+            self.current_song_cached_nothing_playing = true;
+            self.current_song_cached_till = chrono::Utc::now().timestamp_millis() + ttl_ms;
+            self.current_song_cached_at = chrono::Utc::now().timestamp_millis();
+            debug!(ttl_ms, "Cached nothing playing");
             return Ok(None);
         }
 
@@ -131,52 +455,105 @@ impl Spot {
             errored = true;
         }
 
-        let json = serde_json::from_str(&body.unwrap());
+        let body = body.unwrap_or_default();
+        if !status.is_success() {
+            record_spotify_error(status, &body);
+        }
+
+        let json = serde_json::from_str(&body);
         if let Err(err) = &json {
             error!(%err,"Could not parse spotify response to json");
             errored = true;
         }
 
+        self.current_song_cached_types = additional_types;
+        self.current_song_cached_nothing_playing = false;
         if errored {
             self.current_song_cached_response = None;
             self.current_song_cached_till = chrono::Utc::now().timestamp_millis() + TEN_SECONDS;
             self.current_song_cached_at = chrono::Utc::now().timestamp_millis();
+            debug!(ttl_ms = TEN_SECONDS, "Cached current song error");
             return Err(());
         }
 
-        let response_json: CurrentSong = json.unwrap();
-        self.current_song_cached_response = Some(response_json.clone());
-        self.current_song_cached_till = chrono::Utc::now().timestamp_millis()
-            + std::cmp::min(
-                TEN_SECONDS,
-                response_json.item.duration_ms - response_json.progress_ms,
+        self.idle_backoff.reset();
+        let mut response_json: CurrentSong = json.unwrap();
+        response_json.compute_fields();
+        if let (true, PlayingItem::Track(item)) = (response_json.is_playing, &response_json.item) {
+            self.history.record(
+                item,
+                response_json.progress_ms,
+                chrono::Utc::now().timestamp_millis(),
             );
+        }
+        self.current_song_cached_response = Some(response_json.clone());
+        let ttl_ms = current_song_ttl(response_json.remaining_ms());
+        self.current_song_cached_till = chrono::Utc::now().timestamp_millis() + ttl_ms;
+        debug!(ttl_ms, "Cached current song");
 
         self.current_song_cached_at = chrono::Utc::now().timestamp_millis();
         Ok(Some(response_json))
     }
 
+    /// `&market=` param of the account's country. Spotify only includes
+    /// `is_playable` when asked for a market. Without a country, or when the
+    /// profile can't be read, it's left out until the lookup is tried again.
+    async fn market_param(&mut self) -> String {
+        if chrono::Utc::now().timestamp_millis() < self.market_cached_till {
+            if let Some(market) = &self.market_cached_response {
+                return market.clone();
+            }
+        }
+
+        let (market, ttl_ms) = match self.get_profile().await {
+            Ok(Profile {
+                country: Some(country),
+                ..
+            }) => (format!("&market={:}", country), ONE_DAY),
+            Ok(_) => (String::new(), ONE_DAY),
+            Err(_) => (String::new(), TEN_MINUTES),
+        };
+        self.market_cached_response = Some(market.clone());
+        self.market_cached_till = chrono::Utc::now().timestamp_millis() + ttl_ms;
+        market
+    }
+
     #[instrument(skip(self))]
-    pub async fn get_top_songs(&mut self) -> Result<Vec<Item>, ()> {
-        if chrono::Utc::now().timestamp_millis() < self.top_songs_cached_till
-            && self.top_songs_cached_response.is_some()
-        {
-            return Ok(self.top_songs_cached_response.clone().unwrap());
-        } else if chrono::Utc::now().timestamp_millis() < self.top_songs_cached_till {
-            return Err(());
+    pub async fn get_top_songs(&mut self, time_range: TimeRange) -> Result<Vec<Item>, ()> {
+        let cached_till = self
+            .top_songs_cached_till
+            .get(&time_range)
+            .copied()
+            .unwrap_or(0);
+        if chrono::Utc::now().timestamp_millis() < cached_till {
+            let ttl_ms = cached_till - chrono::Utc::now().timestamp_millis();
+            return match self.top_songs_cached_response.get(&time_range) {
+                Some(Some(songs)) => {
+                    debug!(ttl_ms, "Top songs cache hit");
+                    songs.items()
+                }
+                _ => {
+                    debug!(ttl_ms, "Top songs cache holds an error");
+                    Err(())
+                }
+            };
         }
 
-        if chrono::Utc::now().timestamp() > self.auth_expires_at {
-            if let Err(_) = self.get_token().await {
-                return Err(());
-            }
+        debug!("Top songs cache miss");
+
+        let market = self.market_param().await;
+
+        if chrono::Utc::now().timestamp() > self.auth_expires_at && self.get_token().await.is_err()
+        {
+            return Err(());
         }
 
         let client = reqwest::Client::new();
-        let res = client
-            .get("https://api.spotify.com/v1/me/top/tracks?limit=32&time_range=short_term")
-            .header("authorization", format!("Bearer {:}", self.token))
-            .send()
+        let res = self
+            .send(client.get(format!(
+                "{:}/me/top/tracks?limit={:}&time_range={:}{:}",
+                self.api_url, self.top_songs_fetch_limit, time_range, market
+            )))
             .await;
 
         let mut errored = false;
@@ -186,7 +563,8 @@ impl Spot {
         }
 
         let response = res.unwrap();
-        if !response.status().is_success() {
+        let status = response.status();
+        if !status.is_success() {
             error!(?response, "Could not get top song");
             errored = true;
         }
@@ -198,48 +576,120 @@ impl Spot {
             errored = true;
         }
 
-        let json: Result<TopItems, serde_json::Error> = serde_json::from_str(&body.unwrap());
+        let body = body.unwrap_or_default();
+        if !status.is_success() {
+            record_spotify_error(status, &body);
+        }
+        let json: Result<TopItems, serde_json::Error> = serde_json::from_str(&body);
         if let Err(err) = &json {
             error!(%err, "Could not parse spotify response to json");
             errored = true;
         }
 
         if errored {
-            self.top_songs_cached_response = None;
-            self.top_songs_cached_till = chrono::Utc::now().timestamp_millis() + TEN_SECONDS * 2;
+            self.top_songs_cached_response.insert(time_range, None);
+            self.top_songs_cached_till.insert(
+                time_range,
+                chrono::Utc::now().timestamp_millis() + TEN_SECONDS * 2,
+            );
+            debug!(ttl_ms = TEN_SECONDS * 2, "Cached top songs error");
             return Err(());
         }
 
         let json: TopItems = json.unwrap();
 
-        self.top_songs_cached_response = Some(json.items.clone());
-        self.top_songs_cached_till = chrono::Utc::now().timestamp_millis() + TEN_MINUTES;
+        let cached = if self.compress_cache {
+            let compressed = cache::compress(body.as_bytes());
+            debug!(
+                size = body.len(),
+                compressed_size = compressed.len(),
+                "Compressed top songs"
+            );
+            CachedTopSongs::Compressed(compressed)
+        } else {
+            CachedTopSongs::Parsed(json.items.clone())
+        };
+        self.top_songs_cached_response
+            .insert(time_range, Some(cached));
+        self.top_songs_cached_till.insert(
+            time_range,
+            chrono::Utc::now().timestamp_millis() + TEN_MINUTES,
+        );
+        debug!(ttl_ms = TEN_MINUTES, "Cached top songs");
 
         return Ok(json.items);
     }
 
+    /// Recent top songs next to the all time ones, flagging the recent ones that
+    /// aren't all time favourites
     #[instrument(skip(self))]
-    pub async fn update_player_state(&mut self, state: MediaState) -> Result<(), ()> {
-        if chrono::Utc::now().timestamp() > self.auth_expires_at {
-            if let Err(_) = self.get_token().await {
-                return Err(());
+    pub async fn get_top_songs_comparison(
+        &mut self,
+        limit: usize,
+    ) -> Result<TopSongsComparison, ()> {
+        let mut short = self.get_top_songs(TimeRange::Short).await?;
+        let mut long = self.get_top_songs(TimeRange::Long).await?;
+        short.truncate(limit);
+        long.truncate(limit);
+
+        let new_in_short = short
+            .iter()
+            .filter(|song| !long.iter().any(|long_song| long_song.key() == song.key()))
+            .map(|song| song.key().to_string())
+            .collect();
+        Ok(TopSongsComparison {
+            short,
+            long,
+            new_in_short,
+        })
+    }
+
+    /// Merges the top songs of every time range. Each track scores the number of
+    /// tracks ranked below it in each range it appears in, so tracks that are
+    /// high up in several ranges win. Ties keep the order of the shorter range.
+    #[instrument(skip(self))]
+    pub async fn get_combined_top_songs(&mut self) -> Result<Vec<Item>, ()> {
+        let mut combined: Vec<(Item, usize)> = Vec::new();
+        for time_range in [TimeRange::Short, TimeRange::Medium, TimeRange::Long] {
+            let songs = self.get_top_songs(time_range).await?;
+            let count = songs.len();
+            for (position, song) in songs.into_iter().enumerate() {
+                let score = count - position;
+                match combined
+                    .iter_mut()
+                    .find(|(combined, _)| combined.key() == song.key())
+                {
+                    Some((_, combined_score)) => *combined_score += score,
+                    None => combined.push((song, score)),
+                }
             }
         }
 
+        // Stable, so equal scores stay in the order they were first seen in
+        combined.sort_by(|(_, a), (_, b)| b.cmp(a));
+        Ok(combined.into_iter().map(|(song, _)| song).collect())
+    }
+
+    #[instrument(skip(self))]
+    pub async fn update_player_state(&mut self, state: MediaState) -> Result<(), ()> {
+        if chrono::Utc::now().timestamp() > self.auth_expires_at && self.get_token().await.is_err()
+        {
+            return Err(());
+        }
+
+        let device_id = self.active_device_id().await;
         let client = reqwest::Client::new();
         let base_request = match state {
             MediaState::Play | MediaState::Pause => {
-                client.put(format!("https://api.spotify.com/v1/me/player/{:}", state))
+                client.put(format!("{:}/me/player/{:}", self.api_url, state))
             }
             MediaState::Next | MediaState::Previous => {
-                client.post(format!("https://api.spotify.com/v1/me/player/{:}", state))
+                client.post(format!("{:}/me/player/{:}", self.api_url, state))
             }
         };
 
-        let res = base_request
-            .header("authorization", format!("Bearer {:}", self.token))
-            .body(body::Body::from("{}"))
-            .send()
+        let res = self
+            .send_player_write(with_device_id(base_request, device_id).body("{}"))
             .await;
 
         if let Err(error) = &res {
@@ -250,6 +700,7 @@ impl Spot {
         let response = res.unwrap();
         if !response.status().is_success() {
             error!(?response, "Could not change media state");
+            record_failed_response(response).await;
             return Err(());
         }
 
@@ -258,81 +709,3424 @@ impl Spot {
         self.current_song_cached_at = chrono::Utc::now().timestamp_millis();
         Ok(())
     }
-}
 
-#[derive(Debug, Serialize, Deserialize)]
-#[serde(rename_all(serialize = "camelCase"))]
-struct AuthResponse {
-    access_token: String,
-    expires_in: i64,
-}
+    #[instrument(skip(self))]
+    pub async fn seek(&mut self, position_ms: i64, device_id: Option<&str>) -> Result<(), ()> {
+        self.send_seek(position_ms, device_id).await.map_err(|_| ())
+    }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
-#[serde(rename_all(serialize = "camelCase"))]
-pub struct CurrentSong {
-    progress_ms: i64,
-    timestamp: i64,
-    item: Item,
-    is_playing: bool,
-}
+    /// Seeks, telling a missing device (`404`) apart from other failures
+    async fn send_seek(
+        &mut self,
+        position_ms: i64,
+        device_id: Option<&str>,
+    ) -> Result<(), SpotError> {
+        if chrono::Utc::now().timestamp() > self.auth_expires_at {
+            self.get_token().await?;
+        }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
-#[serde(rename_all(serialize = "camelCase"))]
-pub struct Item {
-    name: String,
-    duration_ms: i64,
-    preview_url: Option<String>,
-    album: Album,
-    artists: Vec<Artist>,
-    external_urls: ExternalUrls,
-}
+        let device_id = self.target_device_id(device_id).await;
+        let client = reqwest::Client::new();
+        let res = self
+            .send_player_write(
+                with_device_id(
+                    client.put(format!(
+                        "{:}/me/player/seek?position_ms={:}",
+                        self.api_url, position_ms
+                    )),
+                    device_id,
+                )
+                .body("{}"),
+            )
+            .await;
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
-#[serde(rename_all(serialize = "camelCase"))]
-pub struct Album {
-    album_type: String,
-    artists: Vec<Artist>,
-    external_urls: ExternalUrls,
-    images: Vec<Image>,
-    name: String,
-    uri: String,
-}
+        if let Err(error) = &res {
+            error!(%error, "Could not seek");
+            return Err(SpotError::Unknown);
+        }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
-#[serde(rename_all(serialize = "camelCase"))]
-pub struct Artist {
-    external_urls: ExternalUrls,
-    href: String,
-    name: String,
-    uri: String,
-}
+        let response = res.unwrap();
+        if !response.status().is_success() {
+            error!(?response, "Could not seek");
+            let not_found = response.status() == reqwest::StatusCode::NOT_FOUND;
+            record_failed_response(response).await;
+            return Err(if not_found {
+                SpotError::NotFound
+            } else {
+                SpotError::Unknown
+            });
+        }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
-struct ExternalUrls {
-    spotify: String,
-}
+        self.current_song_cached_response = None;
+        self.current_song_cached_till = chrono::Utc::now().timestamp_millis();
+        self.current_song_cached_at = chrono::Utc::now().timestamp_millis();
+        Ok(())
+    }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
-struct Image {
-    height: i64,
-    url: String,
-    width: i64,
-}
+    /// Sends a player write, retrying it `device_wake_retries` times while
+    /// Spotify answers `404`. A device waking up takes a moment to become
+    /// active, so the first write after pressing play on it often misses it.
+    async fn send_player_write(
+        &mut self,
+        mut request: reqwest::RequestBuilder,
+    ) -> Result<reqwest::Response, reqwest::Error> {
+        let mut attempt = 0;
+        loop {
+            attempt += 1;
+            let retry = if attempt <= self.device_wake_retries {
+                request.try_clone()
+            } else {
+                None
+            };
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct TopItems {
-    items: Vec<Item>,
-}
+            let response = self.send(request).await?;
+            match retry {
+                Some(retry) if response.status() == reqwest::StatusCode::NOT_FOUND => {
+                    debug!(attempt, "No active device yet, retrying player write");
+                    tokio::time::sleep(DEVICE_WAKE_DELAY).await;
+                    request = retry;
+                }
+                _ => return Ok(response),
+            }
+        }
+    }
 
-#[derive(Serialize, Deserialize, Debug, Display, Clone)]
-#[serde(rename_all = "lowercase")]
-pub enum MediaState {
-    #[strum(serialize = "play")]
-    Play,
-    #[strum(serialize = "pause")]
-    Pause,
-    #[strum(serialize = "next")]
-    Next,
-    #[strum(serialize = "previous")]
-    Previous,
+    /// Scopes granted to the refresh token, Spotify returns them with every
+    /// token so they're only fetched when the token expired
+    #[instrument(skip(self))]
+    pub async fn get_scopes(&mut self) -> Result<TokenScopes, SpotError> {
+        if chrono::Utc::now().timestamp() > self.auth_expires_at {
+            self.get_token().await?;
+        }
+
+        Ok(TokenScopes {
+            granted: self.scopes.clone(),
+            missing: USED_SCOPES
+                .into_iter()
+                .filter(|scope| !self.scopes.iter().any(|granted| granted == scope))
+                .collect(),
+        })
+    }
+
+    /// Scope `route` needs but the refresh token wasn't granted. Unknown until
+    /// a token was refreshed, `None` then.
+    pub fn missing_scope(&self, route: &str) -> Option<&'static str> {
+        // Matched routes include the prefix they're nested under
+        let route = route
+            .strip_prefix(PATH_PREFIX.get().map_or("", String::as_str))
+            .filter(|route| !route.is_empty())
+            .unwrap_or("/");
+        ROUTE_SCOPES
+            .into_iter()
+            .find(|(scoped_route, _)| *scoped_route == route)
+            .map(|(_, scope)| scope)
+            .filter(|scope| {
+                !self.scopes.is_empty() && !self.scopes.iter().any(|granted| granted == scope)
+            })
+    }
+
+    #[instrument(skip(self))]
+    pub async fn get_devices(&mut self) -> Result<Vec<Device>, ()> {
+        let devices: Devices = self
+            .get_json(&format!("{:}/me/player/devices", self.api_url))
+            .await?;
+        Ok(devices.devices)
+    }
+
+    /// Id of the active device for writes to target when `REFRESH_ACTIVE_DEVICE`
+    /// is set. Writes without it go to whichever device Spotify last saw, which
+    /// fails when that one went inactive in the meantime.
+    async fn active_device_id(&mut self) -> Option<String> {
+        if !self.refresh_active_device {
+            return None;
+        }
+
+        if chrono::Utc::now().timestamp_millis() >= self.active_device_cached_till {
+            let devices = self.get_devices().await.ok()?;
+            self.active_device_cached_response = devices
+                .into_iter()
+                .find(|device| device.is_active)
+                .and_then(|device| device.id);
+            self.active_device_cached_till =
+                chrono::Utc::now().timestamp_millis() + ACTIVE_DEVICE_TTL;
+        }
+
+        self.active_device_cached_response.clone()
+    }
+
+    /// Id of the device to target, or the active one without
+    async fn target_device_id(&mut self, device_id: Option<&str>) -> Option<String> {
+        match device_id {
+            Some(device_id) => Some(device_id.to_string()),
+            None => self.active_device_id().await,
+        }
+    }
+
+    /// Device and modes of the playback, `None` without any
+    #[instrument(skip(self))]
+    pub async fn get_playback_state(&mut self) -> Result<Option<PlaybackState>, ()> {
+        self.get_json(&format!("{:}/me/player", self.api_url)).await
+    }
+
+    /// Lists the tracks and episodes queued after the current one
+    #[instrument(skip(self))]
+    pub async fn get_queue(&mut self) -> Result<Vec<PlayingItem>, ()> {
+        if chrono::Utc::now().timestamp_millis() < self.queue_cached_till {
+            if let Some(queue) = &self.queue_cached_response {
+                return Ok(queue.clone());
+            }
+        }
+
+        let queue: Queue = self
+            .get_json(&format!("{:}/me/player/queue", self.api_url))
+            .await?;
+
+        self.queue_cached_response = Some(queue.queue.clone());
+        self.queue_cached_till = chrono::Utc::now().timestamp_millis() + TEN_SECONDS;
+        Ok(queue.queue)
+    }
+
+    /// Starts playing `uri`, or resumes playback without one, then seeks to
+    /// `position_ms` when set. The device can take a moment to pick up the new
+    /// playback, so the seek is retried a few times. Episodes are started at the
+    /// position instead, seeking one right after starting it is often ignored.
+    ///
+    /// `shuffle` is set before starting, a context started while shuffling
+    /// begins on a random track rather than shuffling after its first one.
+    #[instrument(skip(self))]
+    pub async fn play_from(
+        &mut self,
+        uri: Option<&str>,
+        position_ms: Option<i64>,
+        shuffle: Option<bool>,
+        device_id: Option<&str>,
+    ) -> Result<(), SpotError> {
+        if let Some(shuffle) = shuffle {
+            self.set_shuffle(shuffle, device_id).await?;
+        }
+
+        if uri.is_some_and(|uri| uri.starts_with("spotify:episode:")) {
+            return self.play(uri, device_id, position_ms).await;
+        }
+
+        self.play(uri, device_id, None).await?;
+        let Some(position_ms) = position_ms else {
+            return Ok(());
+        };
+        // Only a device that isn't ready yet is worth waiting for, any other
+        // failure would fail again
+        let mut attempt = 1;
+        loop {
+            match self.send_seek(position_ms, device_id).await {
+                Err(SpotError::NotFound) if attempt < PLAY_FROM_SEEK_ATTEMPTS => {
+                    debug!(attempt, "Device not ready to seek yet");
+                    tokio::time::sleep(PLAY_FROM_SEEK_DELAY).await;
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    #[instrument(skip(self))]
+    pub async fn set_shuffle(
+        &mut self,
+        shuffle: bool,
+        device_id: Option<&str>,
+    ) -> Result<(), SpotError> {
+        if chrono::Utc::now().timestamp() > self.auth_expires_at {
+            self.get_token().await?;
+        }
+
+        let device_id = self.target_device_id(device_id).await;
+        let client = reqwest::Client::new();
+        let res = self
+            .send_player_write(
+                with_device_id(
+                    client.put(format!(
+                        "{:}/me/player/shuffle?state={:}",
+                        self.api_url, shuffle
+                    )),
+                    device_id,
+                )
+                .body("{}"),
+            )
+            .await;
+
+        if let Err(error) = &res {
+            error!(%error, "Could not set shuffle");
+            return Err(SpotError::Unknown);
+        }
+
+        let response = res.unwrap();
+        match response.status().as_u16() {
+            404 => {
+                error!(?response, "No active device to shuffle on");
+                return Err(SpotError::NotFound);
+            }
+            403 => {
+                error!(?response, "Not allowed to set shuffle");
+                return Err(SpotError::Forbidden);
+            }
+            status if !(200..300).contains(&status) => {
+                error!(?response, "Could not set shuffle");
+                record_failed_response(response).await;
+                return Err(SpotError::Unknown);
+            }
+            _ => {}
+        }
+
+        self.current_song_cached_response = None;
+        self.current_song_cached_till = chrono::Utc::now().timestamp_millis();
+        self.current_song_cached_at = chrono::Utc::now().timestamp_millis();
+        Ok(())
+    }
+
+    #[instrument(skip(self))]
+    pub async fn set_repeat(&mut self, repeat: RepeatMode) -> Result<(), SpotError> {
+        if chrono::Utc::now().timestamp() > self.auth_expires_at {
+            self.get_token().await?;
+        }
+
+        let device_id = self.active_device_id().await;
+        let client = reqwest::Client::new();
+        let res = self
+            .send_player_write(
+                with_device_id(
+                    client.put(format!(
+                        "{:}/me/player/repeat?state={:}",
+                        self.api_url, repeat
+                    )),
+                    device_id,
+                )
+                .body("{}"),
+            )
+            .await;
+
+        if let Err(error) = &res {
+            error!(%error, "Could not set repeat");
+            return Err(SpotError::Unknown);
+        }
+
+        let response = res.unwrap();
+        match response.status().as_u16() {
+            404 => {
+                error!(?response, "No active device to repeat on");
+                Err(SpotError::NotFound)
+            }
+            403 => {
+                error!(?response, "Not allowed to set repeat");
+                Err(SpotError::Forbidden)
+            }
+            status if !(200..300).contains(&status) => {
+                error!(?response, "Could not set repeat");
+                record_failed_response(response).await;
+                Err(SpotError::Unknown)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Starts the connected account's Liked Songs
+    #[instrument(skip(self))]
+    pub async fn play_liked(&mut self) -> Result<(), SpotError> {
+        let uri = self.liked_songs_uri().await?;
+        self.play(Some(&uri), None, None).await
+    }
+
+    /// Context of the connected account's Liked Songs. Playback control needs
+    /// premium, so free accounts are `Forbidden` without asking Spotify.
+    pub async fn liked_songs_uri(&mut self) -> Result<String, SpotError> {
+        let profile = self.get_profile().await.map_err(|_| SpotError::Unknown)?;
+        if profile
+            .product
+            .as_deref()
+            .is_some_and(|product| product != "premium")
+        {
+            return Err(SpotError::Forbidden);
+        }
+
+        Ok(format!("spotify:user:{:}:collection", profile.id))
+    }
+
+    /// Starts playing `uri` on the device, or resumes playback there without one.
+    /// `position_ms` only applies to single tracks and episodes, contexts start
+    /// at their beginning.
+    #[instrument(skip(self))]
+    pub async fn play(
+        &mut self,
+        uri: Option<&str>,
+        device_id: Option<&str>,
+        position_ms: Option<i64>,
+    ) -> Result<(), SpotError> {
+        if chrono::Utc::now().timestamp() > self.auth_expires_at {
+            self.get_token().await?;
+        }
+
+        let body = match uri {
+            Some(uri)
+                if uri.starts_with("spotify:track:") || uri.starts_with("spotify:episode:") =>
+            {
+                match position_ms {
+                    Some(position_ms) => {
+                        serde_json::json!({ "uris": [uri], "position_ms": position_ms })
+                    }
+                    None => serde_json::json!({ "uris": [uri] }),
+                }
+            }
+            Some(uri) => serde_json::json!({ "context_uri": uri }),
+            None => serde_json::json!({}),
+        };
+
+        let mut url = reqwest::Url::parse(&format!("{:}/me/player/play", self.api_url)).unwrap();
+        if let Some(device_id) = self.target_device_id(device_id).await {
+            url.query_pairs_mut().append_pair("device_id", &device_id);
+        }
+
+        let client = reqwest::Client::new();
+        let res = self
+            .send_player_write(
+                client
+                    .put(url)
+                    .header("Content-Type", "application/json")
+                    .body(body.to_string()),
+            )
+            .await;
+
+        if let Err(error) = &res {
+            error!(%error, "Could not start playback");
+            return Err(SpotError::Unknown);
+        }
+
+        let response = res.unwrap();
+        match response.status().as_u16() {
+            404 => {
+                error!(?response, "Device not found");
+                return Err(SpotError::NotFound);
+            }
+            403 => {
+                error!(?response, "Not allowed to start playback");
+                return Err(SpotError::Forbidden);
+            }
+            status if !(200..300).contains(&status) => {
+                error!(?response, "Could not start playback");
+                record_failed_response(response).await;
+                return Err(SpotError::Unknown);
+            }
+            _ => {}
+        }
+
+        self.current_song_cached_response = None;
+        self.current_song_cached_till = chrono::Utc::now().timestamp_millis();
+        self.current_song_cached_at = chrono::Utc::now().timestamp_millis();
+        Ok(())
+    }
+
+    #[instrument(skip(self))]
+    pub async fn remove_from_playlist(
+        &mut self,
+        playlist_id: &str,
+        track_uri: &str,
+    ) -> Result<(), SpotError> {
+        if chrono::Utc::now().timestamp() > self.auth_expires_at {
+            self.get_token().await?;
+        }
+
+        let client = reqwest::Client::new();
+        let res = self
+            .send(
+                client
+                    .delete(format!(
+                        "{:}/playlists/{:}/tracks",
+                        self.api_url, playlist_id
+                    ))
+                    .header("Content-Type", "application/json")
+                    .body(serde_json::json!({ "tracks": [{ "uri": track_uri }] }).to_string()),
+            )
+            .await;
+
+        if let Err(error) = &res {
+            error!(%error, "Could not remove track from playlist");
+            return Err(SpotError::Unknown);
+        }
+
+        let response = res.unwrap();
+        match response.status().as_u16() {
+            // Spotify answers malformed playlist ids with a 400
+            400 | 404 => {
+                error!(?response, "Spotify rejected the playlist");
+                Err(SpotError::NotFound)
+            }
+            403 => {
+                error!(?response, "Not allowed to modify the playlist");
+                Err(SpotError::Forbidden)
+            }
+            status if !(200..300).contains(&status) => {
+                error!(?response, "Could not remove track from playlist");
+                record_failed_response(response).await;
+                Err(SpotError::Unknown)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Moves the `range_length` tracks from `range_start` in front of the track
+    /// at `insert_before`
+    #[instrument(skip(self))]
+    pub async fn reorder_playlist(
+        &mut self,
+        playlist_id: &str,
+        range_start: usize,
+        insert_before: usize,
+        range_length: usize,
+    ) -> Result<(), SpotError> {
+        if chrono::Utc::now().timestamp() > self.auth_expires_at {
+            self.get_token().await?;
+        }
+
+        let client = reqwest::Client::new();
+        let res = self
+            .send(
+                client
+                    .put(format!(
+                        "{:}/playlists/{:}/tracks",
+                        self.api_url, playlist_id
+                    ))
+                    .header("Content-Type", "application/json")
+                    .body(
+                        serde_json::json!({
+                            "range_start": range_start,
+                            "insert_before": insert_before,
+                            "range_length": range_length,
+                        })
+                        .to_string(),
+                    ),
+            )
+            .await;
+
+        if let Err(error) = &res {
+            error!(%error, "Could not reorder playlist");
+            return Err(SpotError::Unknown);
+        }
+
+        let response = res.unwrap();
+        match response.status().as_u16() {
+            // Spotify answers malformed playlist ids with a 400
+            400 | 404 => {
+                error!(?response, "Spotify rejected the playlist");
+                Err(SpotError::NotFound)
+            }
+            403 => {
+                error!(?response, "Not allowed to modify the playlist");
+                Err(SpotError::Forbidden)
+            }
+            status if !(200..300).contains(&status) => {
+                error!(?response, "Could not reorder playlist");
+                record_failed_response(response).await;
+                Err(SpotError::Unknown)
+            }
+            _ => {
+                // The cached playlists hold the snapshot from before the reorder
+                self.playlists_cached_response = None;
+                self.playlists_cached_till = 0;
+                Ok(())
+            }
+        }
+    }
+
+    /// Number of tracks in one of the connected account's playlists, `None`
+    /// for playlists it doesn't own or follow
+    pub async fn get_playlist_length(&mut self, playlist_id: &str) -> Result<Option<usize>, ()> {
+        Ok(self
+            .get_playlists()
+            .await?
+            .into_iter()
+            .find(|playlist| playlist.id == playlist_id)
+            .map(|playlist| playlist.tracks.total))
+    }
+
+    #[instrument(skip(self))]
+    pub async fn get_artists(&mut self, ids: &[String]) -> Result<Vec<FullArtist>, ()> {
+        let missing_ids = ids
+            .iter()
+            .filter(|id| self.artists_cache.get(id).is_none())
+            .cloned()
+            .collect::<Vec<String>>();
+
+        if !missing_ids.is_empty() {
+            let artists: Artists = self
+                .get_json(&format!(
+                    "{:}/artists?ids={:}",
+                    self.api_url,
+                    missing_ids.join(",")
+                ))
+                .await?;
+
+            // Unknown ids come back as null
+            for artist in artists.artists.into_iter().flatten() {
+                self.artists_cache.insert(artist.id.clone(), artist);
+            }
+        }
+
+        Ok(ids
+            .iter()
+            .filter_map(|id| self.artists_cache.get(id))
+            .collect())
+    }
+
+    #[instrument(skip(self))]
+    pub async fn get_followed_artists(&mut self, limit: usize) -> Result<Vec<FullArtist>, ()> {
+        if chrono::Utc::now().timestamp_millis() < self.followed_artists_cached_till {
+            if let Some(artists) = &self.followed_artists_cached_response {
+                if artists.len() >= limit || self.followed_artists_complete {
+                    return Ok(artists.iter().take(limit).cloned().collect());
+                }
+            }
+        }
+
+        // The `next` link of followed artists carries the `after` cursor rather than an offset
+        let artists = self
+            .fetch_all_pages(
+                format!(
+                    "{:}/me/following?type=artist&limit={:}",
+                    self.api_url, MAX_PAGE_SIZE
+                ),
+                limit,
+                |followed: FollowedArtists| followed.artists,
+            )
+            .await?;
+
+        self.followed_artists_complete = artists.len() < limit;
+        self.followed_artists_cached_response = Some(artists.clone());
+        self.followed_artists_cached_till = chrono::Utc::now().timestamp_millis() + TEN_MINUTES / 2;
+        Ok(artists)
+    }
+
+    #[instrument(skip(self))]
+    pub async fn get_saved_albums(&mut self, limit: usize) -> Result<Vec<SavedAlbum>, ()> {
+        if chrono::Utc::now().timestamp_millis() < self.saved_albums_cached_till {
+            if let Some(albums) = &self.saved_albums_cached_response {
+                if albums.len() >= limit || self.saved_albums_complete {
+                    return Ok(albums.iter().take(limit).cloned().collect());
+                }
+            }
+        }
+
+        let albums = self
+            .fetch_all_pages(
+                format!("{:}/me/albums?limit={:}", self.api_url, MAX_PAGE_SIZE),
+                limit,
+                |page: Page<SavedAlbum>| page,
+            )
+            .await?;
+
+        self.saved_albums_complete = albums.len() < limit;
+        self.saved_albums_cached_response = Some(albums.clone());
+        self.saved_albums_cached_till = chrono::Utc::now().timestamp_millis() + TEN_MINUTES / 2;
+        Ok(albums)
+    }
+
+    /// Podcasts the connected account follows, empty when it follows none
+    #[instrument(skip(self))]
+    pub async fn get_saved_shows(&mut self, limit: usize) -> Result<Vec<SavedShow>, ()> {
+        if chrono::Utc::now().timestamp_millis() < self.saved_shows_cached_till {
+            if let Some(shows) = &self.saved_shows_cached_response {
+                if shows.len() >= limit || self.saved_shows_complete {
+                    return Ok(shows.iter().take(limit).cloned().collect());
+                }
+            }
+        }
+
+        let shows = self
+            .fetch_all_pages(
+                format!("{:}/me/shows?limit={:}", self.api_url, MAX_PAGE_SIZE),
+                limit,
+                |page: Page<SavedShow>| page,
+            )
+            .await?;
+
+        self.saved_shows_complete = shows.len() < limit;
+        self.saved_shows_cached_response = Some(shows.clone());
+        self.saved_shows_cached_till = chrono::Utc::now().timestamp_millis() + TEN_MINUTES / 2;
+        Ok(shows)
+    }
+
+    /// Playlists the connected account owns or follows, up to `MAX_PAGES` pages
+    #[instrument(skip(self))]
+    pub async fn get_playlists(&mut self) -> Result<Vec<Playlist>, ()> {
+        if chrono::Utc::now().timestamp_millis() < self.playlists_cached_till {
+            if let Some(playlists) = &self.playlists_cached_response {
+                return Ok(playlists.clone());
+            }
+        }
+
+        let playlists = self
+            .fetch_all_pages(
+                format!("{:}/me/playlists?limit={:}", self.api_url, MAX_PAGE_SIZE),
+                usize::MAX,
+                |page: Page<Playlist>| page,
+            )
+            .await?;
+
+        self.playlists_cached_response = Some(playlists.clone());
+        self.playlists_cached_till = chrono::Utc::now().timestamp_millis() + TEN_MINUTES / 2;
+        Ok(playlists)
+    }
+
+    /// The connected account's playlists containing the current track, `None`
+    /// when no track is playing. Only playlists whose tracks are cached are
+    /// scanned, each call fetches the tracks of a few more.
+    #[instrument(skip(self))]
+    pub async fn get_playlists_with_current(&mut self) -> Result<Option<Vec<Playlist>>, ()> {
+        let track_id = match self.get_current_song(&[AdditionalType::Track]).await? {
+            Some(CurrentSong {
+                item: PlayingItem::Track(item),
+                ..
+            }) => item.control_id().map(String::from),
+            _ => None,
+        };
+        let Some(track_id) = track_id else {
+            return Ok(None);
+        };
+
+        let mut fetched = 0;
+        let mut containing = Vec::new();
+        for playlist in self.get_playlists().await? {
+            let key = (playlist.id.clone(), playlist.snapshot_id.clone());
+            let track_ids = match self.playlist_tracks_cache.get(&key) {
+                Some(track_ids) => track_ids,
+                // The id goes into the url, so even the ones from Spotify are checked
+                None if fetched < PLAYLISTS_SCANNED_PER_REQUEST && is_spotify_id(&playlist.id) => {
+                    fetched += 1;
+                    let track_ids = self.get_playlist_track_ids(&playlist.id).await?;
+                    self.playlist_tracks_cache.insert(key, track_ids.clone());
+                    track_ids
+                }
+                None => continue,
+            };
+            if track_ids.contains(&track_id) {
+                containing.push(playlist);
+            }
+        }
+        Ok(Some(containing))
+    }
+
+    /// Ids of the tracks in a playlist, up to `max_pages` pages of them. Only
+    /// the ids are asked for, the full tracks are most of the payload.
+    async fn get_playlist_track_ids(&mut self, playlist_id: &str) -> Result<HashSet<String>, ()> {
+        let tracks = self
+            .fetch_all_pages(
+                format!(
+                    "{:}/playlists/{:}/tracks?limit={:}&fields=next,items(track(id))",
+                    self.api_url, playlist_id, MAX_PAGE_SIZE
+                ),
+                usize::MAX,
+                |page: Page<PlaylistTrackId>| page,
+            )
+            .await?;
+
+        // Removed tracks are `null` and local files have no id
+        Ok(tracks
+            .into_iter()
+            .filter_map(|playlist_track| playlist_track.track?.id)
+            .collect())
+    }
+
+    /// The connected account's playlists with `query` in their name, ignoring case
+    #[instrument(skip(self))]
+    pub async fn search_playlists(&mut self, query: &str) -> Result<Vec<Playlist>, ()> {
+        let query = query.to_lowercase();
+        Ok(self
+            .get_playlists()
+            .await?
+            .into_iter()
+            .filter(|playlist| playlist.name.to_lowercase().contains(&query))
+            .collect())
+    }
+
+    /// Liked tracks, most recently liked first, starting `offset` tracks in
+    #[instrument(skip(self))]
+    pub async fn get_liked_tracks(
+        &mut self,
+        limit: usize,
+        offset: usize,
+    ) -> Result<Vec<LikedTrack>, ()> {
+        if let Some(tracks) = self.liked_tracks_cache.get(&(offset, limit)) {
+            return Ok(tracks);
+        }
+
+        let tracks = self
+            .fetch_all_pages(
+                format!(
+                    "{:}/me/tracks?limit={:}&offset={:}",
+                    self.api_url, MAX_PAGE_SIZE, offset
+                ),
+                limit,
+                |page: Page<LikedTrack>| page,
+            )
+            .await?;
+
+        self.liked_tracks_cache
+            .insert((offset, limit), tracks.clone());
+        Ok(tracks)
+    }
+
+    /// Whether each of the track ids is liked, only asking Spotify about the
+    /// ones not cached yet
+    #[instrument(skip(self))]
+    pub async fn get_liked_ids(&mut self, ids: &[String]) -> Result<HashMap<String, bool>, ()> {
+        let missing_ids = ids
+            .iter()
+            .filter(|id| self.liked_ids_cache.get(id).is_none())
+            .cloned()
+            .collect::<Vec<String>>();
+
+        if !missing_ids.is_empty() {
+            // In the order of the ids asked about
+            let liked: Vec<bool> = self
+                .get_json(&format!(
+                    "{:}/me/tracks/contains?ids={:}",
+                    self.api_url,
+                    missing_ids.join(",")
+                ))
+                .await?;
+
+            for (id, liked) in missing_ids.into_iter().zip(liked) {
+                self.liked_ids_cache.insert(id, liked);
+            }
+        }
+
+        Ok(ids
+            .iter()
+            .filter_map(|id| Some((id.clone(), self.liked_ids_cache.get(id)?)))
+            .collect())
+    }
+
+    #[instrument(skip(self))]
+    pub async fn get_genre_seeds(&mut self) -> Result<Vec<String>, ()> {
+        if chrono::Utc::now().timestamp_millis() < self.genre_seeds_cached_till {
+            if let Some(genres) = &self.genre_seeds_cached_response {
+                return Ok(genres.clone());
+            }
+        }
+
+        let seeds: GenreSeeds = self
+            .get_json(&format!(
+                "{:}/recommendations/available-genre-seeds",
+                self.api_url
+            ))
+            .await?;
+
+        // The seeds barely ever change
+        self.genre_seeds_cached_response = Some(seeds.genres.clone());
+        self.genre_seeds_cached_till = chrono::Utc::now().timestamp_millis() + ONE_DAY;
+        Ok(seeds.genres)
+    }
+
+    /// Queues `count` recommendations seeded from the current track, returning
+    /// them. `None` when no track is playing to seed them from.
+    #[instrument(skip(self))]
+    pub async fn queue_recommendations(
+        &mut self,
+        count: usize,
+    ) -> Result<Option<Vec<Item>>, SpotError> {
+        let track_id = match self.get_current_song(&[AdditionalType::Track]).await {
+            Ok(Some(CurrentSong {
+                item: PlayingItem::Track(Item { id: Some(id), .. }),
+                ..
+            })) => id,
+            Ok(_) => return Ok(None),
+            Err(_) => return Err(SpotError::Unknown),
+        };
+
+        let params = [
+            ("seed_tracks".to_string(), track_id),
+            ("limit".to_string(), count.to_string()),
+        ];
+        let tracks = self
+            .get_recommendations(&params)
+            .await
+            .map_err(|_| SpotError::Unknown)?;
+
+        // One request per track, each waits on the scheduler like any other
+        for track in &tracks {
+            if let Some(uri) = &track.uri {
+                self.add_to_queue(uri).await?;
+            }
+        }
+
+        Ok(Some(tracks))
+    }
+
+    #[instrument(skip(self))]
+    pub async fn add_to_queue(&mut self, uri: &str) -> Result<(), SpotError> {
+        if chrono::Utc::now().timestamp() > self.auth_expires_at {
+            self.get_token().await?;
+        }
+
+        let device_id = self.active_device_id().await;
+        let client = reqwest::Client::new();
+        let res = self
+            .send_player_write(
+                with_device_id(
+                    client
+                        .post(format!("{:}/me/player/queue", self.api_url))
+                        .query(&[("uri", uri)]),
+                    device_id,
+                )
+                .body("{}"),
+            )
+            .await;
+
+        if let Err(error) = &res {
+            error!(%error, "Could not add to queue");
+            return Err(SpotError::Unknown);
+        }
+
+        let response = res.unwrap();
+        match response.status().as_u16() {
+            404 => {
+                error!(?response, "No active device to queue on");
+                return Err(SpotError::NotFound);
+            }
+            403 => {
+                error!(?response, "Not allowed to add to queue");
+                return Err(SpotError::Forbidden);
+            }
+            status if !(200..300).contains(&status) => {
+                error!(?response, "Could not add to queue");
+                record_failed_response(response).await;
+                return Err(SpotError::Unknown);
+            }
+            _ => {}
+        }
+
+        self.queue_cached_response = None;
+        self.queue_cached_till = 0;
+        Ok(())
+    }
+
+    #[instrument(skip(self))]
+    pub async fn get_recommendations(
+        &mut self,
+        params: &[(String, String)],
+    ) -> Result<Vec<Item>, ()> {
+        let url =
+            reqwest::Url::parse_with_params(&format!("{:}/recommendations", self.api_url), params);
+        if let Err(err) = &url {
+            error!(%err, "Could not build recommendations url");
+            return Err(());
+        }
+
+        let recommendations: Recommendations = self.get_json(url.unwrap().as_str()).await?;
+        Ok(recommendations.tracks)
+    }
+
+    #[instrument(skip(self))]
+    pub async fn get_profile(&mut self) -> Result<Profile, ()> {
+        if chrono::Utc::now().timestamp_millis() < self.profile_cached_till {
+            if let Some(profile) = &self.profile_cached_response {
+                return Ok(profile.clone());
+            }
+        }
+
+        let profile: Profile = self.get_json(&format!("{:}/me", self.api_url)).await?;
+        self.profile_cached_response = Some(profile.clone());
+        self.profile_cached_till = chrono::Utc::now().timestamp_millis() + TEN_MINUTES * 6;
+        Ok(profile)
+    }
+
+    #[instrument(skip(self))]
+    pub async fn get_new_releases(
+        &mut self,
+        limit: usize,
+        country: Option<String>,
+    ) -> Result<Vec<Album>, ()> {
+        let country = match country {
+            Some(country) => country,
+            // Without the user-read-private scope the profile has no country
+            None => self.get_profile().await?.country.unwrap_or("US".into()),
+        };
+
+        if let Some(albums) = self.new_releases_cache.get(&country) {
+            if albums.len() >= limit {
+                return Ok(albums.into_iter().take(limit).collect());
+            }
+        }
+
+        let albums = self
+            .fetch_all_pages(
+                format!(
+                    "{:}/browse/new-releases?country={:}&limit={:}",
+                    self.api_url, country, MAX_PAGE_SIZE
+                ),
+                limit,
+                |new_releases: NewReleases| new_releases.albums,
+            )
+            .await?;
+
+        self.new_releases_cache.insert(country, albums.clone());
+        Ok(albums)
+    }
+
+    #[instrument(skip(self))]
+    pub async fn get_featured_playlists(&mut self, limit: usize) -> Result<FeaturedPlaylists, ()> {
+        if chrono::Utc::now().timestamp_millis() < self.featured_playlists_cached_till {
+            if let Some(featured) = &self.featured_playlists_cached_response {
+                if featured.playlists.len() >= limit {
+                    return Ok(FeaturedPlaylists {
+                        message: featured.message.clone(),
+                        playlists: featured.playlists.iter().take(limit).cloned().collect(),
+                    });
+                }
+            }
+        }
+
+        let featured: FeaturedPlaylistsResponse = self
+            .get_json(&format!(
+                "{:}/browse/featured-playlists?limit={:}",
+                self.api_url, limit
+            ))
+            .await?;
+        let featured = FeaturedPlaylists {
+            message: featured.message,
+            playlists: featured.playlists.items,
+        };
+
+        self.featured_playlists_cached_response = Some(featured.clone());
+        self.featured_playlists_cached_till =
+            chrono::Utc::now().timestamp_millis() + TEN_MINUTES * 3;
+        Ok(featured)
+    }
+
+    #[instrument(skip(self))]
+    pub async fn get_health(&mut self) -> SpotifyHealth {
+        if chrono::Utc::now().timestamp_millis() < self.health_cached_till {
+            if let Some(health) = &self.health_cached_response {
+                return health.clone();
+            }
+        }
+
+        let started_at = std::time::Instant::now();
+        let reachable = self.ping().await;
+        let health = SpotifyHealth {
+            reachable,
+            latency_ms: started_at.elapsed().as_millis() as u64,
+        };
+
+        self.health_cached_response = Some(health.clone());
+        self.health_cached_till = chrono::Utc::now().timestamp_millis() + TEN_SECONDS;
+        health
+    }
+
+    /// Canvas of the current track looked up with the configured provider,
+    /// `None` without a provider, track or canvas
+    #[instrument(skip(self))]
+    pub async fn get_canvas(&mut self) -> Result<Option<String>, ()> {
+        let Some(provider_url) = self.canvas_provider_url.clone() else {
+            return Ok(None);
+        };
+
+        let track_id = match self.get_current_song(&[AdditionalType::Track]).await? {
+            Some(CurrentSong {
+                item: PlayingItem::Track(Item { id: Some(id), .. }),
+                ..
+            }) => id,
+            _ => return Ok(None),
+        };
+
+        if let Some(canvas_url) = self.canvas_cache.get(&track_id) {
+            return Ok(canvas_url);
+        }
+
+        // Canvas isn't part of the public api, so it isn't rate limited by the scheduler
+        let res = download::client()
+            .get(provider_url.replace("{track_id}", &track_id))
+            .send()
+            .await;
+        let canvas_url = match res {
+            Ok(response) if response.status() == reqwest::StatusCode::NOT_FOUND => None,
+            Ok(response) if response.status().is_success() => {
+                let body = response.text().await.unwrap_or_default();
+                match serde_json::from_str::<CanvasResponse>(&body) {
+                    Ok(canvas) => canvas.canvas_url,
+                    Err(err) => {
+                        error!(%err, "Could not parse canvas provider response");
+                        return Err(());
+                    }
+                }
+            }
+            Ok(response) => {
+                error!(?response, "Canvas provider request failed");
+                return Err(());
+            }
+            Err(error) => {
+                error!(%error, "Could not reach canvas provider");
+                return Err(());
+            }
+        };
+
+        self.canvas_cache.insert(track_id, canvas_url.clone());
+        Ok(canvas_url)
+    }
+
+    /// Timed lyrics of the current track looked up with the configured provider,
+    /// `None` without a provider, track or synced lyrics. The provider gets the
+    /// duration as well, to tell apart versions of a song sharing its lyrics.
+    #[instrument(skip(self))]
+    pub async fn get_synced_lyrics(&mut self) -> Result<Option<SyncedLyrics>, ()> {
+        let Some(provider_url) = self.synced_lyrics_provider_url.clone() else {
+            return Ok(None);
+        };
+
+        let (track_id, duration_ms) = match self.get_current_song(&[AdditionalType::Track]).await? {
+            Some(CurrentSong {
+                item:
+                    PlayingItem::Track(Item {
+                        id: Some(id),
+                        duration_ms,
+                        ..
+                    }),
+                ..
+            }) => (id, duration_ms),
+            _ => return Ok(None),
+        };
+
+        if let Some(lyrics) = self.synced_lyrics_cache.get(&track_id) {
+            return Ok(lyrics);
+        }
+
+        let res = download::client()
+            .get(
+                provider_url
+                    .replace("{track_id}", &track_id)
+                    .replace("{duration_ms}", &duration_ms.to_string()),
+            )
+            .send()
+            .await;
+        let lyrics = match res {
+            Ok(response) if response.status() == reqwest::StatusCode::NOT_FOUND => None,
+            Ok(response) if response.status().is_success() => {
+                let body = match download::read_capped(response, MAX_SYNCED_LYRICS_BYTES).await {
+                    Ok(Some(body)) => body,
+                    Ok(None) => {
+                        error!("Synced lyrics provider response too large");
+                        return Err(());
+                    }
+                    Err(err) => {
+                        error!(%err, "Could not read synced lyrics provider response");
+                        return Err(());
+                    }
+                };
+                match serde_json::from_slice::<SyncedLyrics>(&body) {
+                    // Some providers answer lyrics without timing with no lines
+                    Ok(lyrics) if lyrics.lines.is_empty() => None,
+                    Ok(lyrics) => Some(lyrics),
+                    Err(err) => {
+                        error!(%err, "Could not parse synced lyrics provider response");
+                        return Err(());
+                    }
+                }
+            }
+            Ok(response) => {
+                error!(?response, "Synced lyrics provider request failed");
+                return Err(());
+            }
+            Err(error) => {
+                error!(%error, "Could not reach synced lyrics provider");
+                return Err(());
+            }
+        };
+
+        self.synced_lyrics_cache.insert(track_id, lyrics.clone());
+        Ok(lyrics)
+    }
+
+    /// Art of the current track to take the palette of, `None` when nothing is
+    /// playing. The palette is fetched and extracted without holding the state
+    /// lock, then cached with `cache_palette`.
+    #[instrument(skip(self))]
+    pub async fn get_palette_art_url(&mut self) -> Result<Option<String>, ()> {
+        Ok(self
+            .get_current_song(&[AdditionalType::Track])
+            .await?
+            .and_then(|current_song| {
+                current_song
+                    .image(ImageSize::Small)
+                    .map(|image| image.url.clone())
+            }))
+    }
+
+    pub fn cached_palette(&mut self, image_url: &str) -> Option<Palette> {
+        self.palettes_cache.get(&image_url.to_string())
+    }
+
+    pub fn cache_palette(&mut self, image_url: String, palette: Palette) {
+        self.palettes_cache.insert(image_url, palette);
+    }
+
+    /// Tracks played before or after the `cursor`, or the latest ones without
+    /// one. Pages with a cursor aren't cached, the history they page through
+    /// doesn't change but which pages are asked for differs every time.
+    #[instrument(skip(self))]
+    pub async fn get_recently_played(
+        &mut self,
+        limit: usize,
+        cursor: Option<PlayedCursor>,
+    ) -> Result<RecentlyPlayed, ()> {
+        let limit = limit.min(MAX_PAGE_SIZE);
+        if cursor.is_none() {
+            if let Some(recently_played) = self.recently_played_cache.get(&limit) {
+                return Ok(recently_played);
+            }
+        }
+
+        let mut url = format!(
+            "{:}/me/player/recently-played?limit={:}",
+            self.api_url, limit
+        );
+        match cursor {
+            Some(PlayedCursor::Before(before)) => url += &format!("&before={:}", before),
+            Some(PlayedCursor::After(after)) => url += &format!("&after={:}", after),
+            None => {}
+        }
+        let recently_played: RecentlyPlayed = self.get_json(&url).await?;
+
+        if cursor.is_none() {
+            self.recently_played_cache
+                .insert(limit, recently_played.clone());
+        }
+        Ok(recently_played)
+    }
+
+    /// Plays in the history per hour of the day in the configured timezone
+    pub fn get_hourly_stats(&self) -> [usize; 24] {
+        self.history.hourly(self.timezone)
+    }
+
+    /// Sections and beats of the track, everything else Spotify analyses is dropped
+    #[instrument(skip(self))]
+    pub async fn get_audio_analysis(&mut self, track_id: &str) -> Result<AudioAnalysis, ()> {
+        if let Some(analysis) = self.audio_analyses_cache.get(&track_id.to_string()) {
+            return Ok(analysis);
+        }
+
+        let analysis: AudioAnalysis = self
+            .get_json(&format!("{:}/audio-analysis/{:}", self.api_url, track_id))
+            .await?;
+        self.audio_analyses_cache
+            .insert(track_id.to_string(), analysis.clone());
+        Ok(analysis)
+    }
+
+    /// Averages of the audio features of the recent top songs, all `None` when
+    /// there are no top songs or none of them have features
+    #[instrument(skip(self))]
+    pub async fn get_taste(&mut self) -> Result<Taste, ()> {
+        let ids = self
+            .get_top_songs(TimeRange::Short)
+            .await?
+            .into_iter()
+            .filter_map(|song| song.id)
+            .collect::<Vec<String>>();
+
+        let missing_ids = ids
+            .iter()
+            .filter(|id| self.audio_features_cache.get(id).is_none())
+            .cloned()
+            .collect::<Vec<String>>();
+        for batch in missing_ids.chunks(AUDIO_FEATURES_BATCH_SIZE) {
+            // In the order of the ids asked about, null for tracks without features
+            let features: AudioFeaturesList = self
+                .get_json(&format!(
+                    "{:}/audio-features?ids={:}",
+                    self.api_url,
+                    batch.join(",")
+                ))
+                .await?;
+            for (id, features) in batch.iter().zip(features.audio_features) {
+                self.audio_features_cache.insert(id.clone(), features);
+            }
+        }
+
+        let features = ids
+            .iter()
+            .filter_map(|id| self.audio_features_cache.get(id).flatten())
+            .collect::<Vec<AudioFeatures>>();
+        let average = |feature: fn(&AudioFeatures) -> f64| {
+            (!features.is_empty())
+                .then(|| features.iter().map(feature).sum::<f64>() / features.len() as f64)
+        };
+        Ok(Taste {
+            energy: average(|features| features.energy),
+            valence: average(|features| features.valence),
+            danceability: average(|features| features.danceability),
+            tempo: average(|features| features.tempo),
+            acousticness: average(|features| features.acousticness),
+            tracks: features.len(),
+        })
+    }
+
+    /// Preview mp3 of the track, `None` for tracks without one
+    #[instrument(skip(self))]
+    pub async fn get_preview_url(&mut self, track_id: &str) -> Result<Option<String>, ()> {
+        if let Some(preview_url) = self.preview_urls_cache.get(&track_id.to_string()) {
+            return Ok(preview_url);
+        }
+
+        let track: Item = self
+            .get_json(&format!("{:}/tracks/{:}", self.api_url, track_id))
+            .await?;
+        self.preview_urls_cache
+            .insert(track_id.to_string(), track.preview_url.clone());
+        Ok(track.preview_url)
+    }
+
+    /// Tracks seen playing since startup, most recent first
+    pub fn get_history(&self) -> Vec<HistoryEntry> {
+        self.history.entries()
+    }
+
+    /// Drops every cached response, returning the names of the caches cleared
+    pub fn clear_caches(&mut self) -> Vec<&'static str> {
+        self.current_song_cached_response = None;
+        self.current_song_cached_till = 0;
+        self.current_song_cached_at = 0;
+        self.current_song_cached_nothing_playing = false;
+        self.top_songs_cached_response.clear();
+        self.top_songs_cached_till.clear();
+        self.artists_cache.clear();
+        self.followed_artists_cached_response = None;
+        self.followed_artists_cached_till = 0;
+        self.active_device_cached_response = None;
+        self.active_device_cached_till = 0;
+        self.queue_cached_response = None;
+        self.queue_cached_till = 0;
+        self.saved_albums_cached_response = None;
+        self.saved_albums_cached_till = 0;
+        self.saved_shows_cached_response = None;
+        self.saved_shows_cached_till = 0;
+        self.playlists_cached_response = None;
+        self.playlists_cached_till = 0;
+        self.genre_seeds_cached_response = None;
+        self.genre_seeds_cached_till = 0;
+        self.profile_cached_response = None;
+        self.profile_cached_till = 0;
+        self.market_cached_response = None;
+        self.market_cached_till = 0;
+        self.new_releases_cache.clear();
+        self.canvas_cache.clear();
+        self.synced_lyrics_cache.clear();
+        self.preview_urls_cache.clear();
+        self.palettes_cache.clear();
+        self.recently_played_cache.clear();
+        self.liked_tracks_cache.clear();
+        self.liked_ids_cache.clear();
+        self.audio_analyses_cache.clear();
+        self.audio_features_cache.clear();
+        self.playlist_tracks_cache.clear();
+        self.featured_playlists_cached_response = None;
+        self.featured_playlists_cached_till = 0;
+        self.health_cached_response = None;
+        self.health_cached_till = 0;
+
+        vec![
+            "current_song",
+            "top_songs",
+            "artists",
+            "followed_artists",
+            "active_device",
+            "queue",
+            "saved_albums",
+            "saved_shows",
+            "playlists",
+            "genre_seeds",
+            "profile",
+            "market",
+            "new_releases",
+            "canvas",
+            "synced_lyrics",
+            "preview_urls",
+            "palettes",
+            "recently_played",
+            "liked_tracks",
+            "liked_ids",
+            "audio_analyses",
+            "audio_features",
+            "playlist_tracks",
+            "featured_playlists",
+            "health",
+        ]
+    }
+
+    pub fn cache_status(&self) -> CacheStatus {
+        let now = chrono::Utc::now().timestamp_millis();
+        CacheStatus {
+            current_song: CacheExpiry::new(self.current_song_cached_till, now),
+            top_songs: CacheExpiry::new(
+                self.top_songs_cached_till
+                    .get(&TimeRange::Short)
+                    .copied()
+                    .unwrap_or(0),
+                now,
+            ),
+            token: CacheExpiry::new(self.auth_expires_at * 1000, now),
+        }
+    }
+
+    /// The token, current song and top songs with how long they're cached
+    /// for, the caches worth handing to the next instance on a redeploy
+    pub fn export_caches(&self) -> CacheSnapshot {
+        let now = chrono::Utc::now().timestamp_millis();
+        let token = (!self.token.is_empty()).then(|| TokenSnapshot {
+            token: self.token.clone(),
+            scopes: self.scopes.clone(),
+            expires_at: self.auth_expires_at,
+        });
+        let current_song = self
+            .current_song_cached_response
+            .as_ref()
+            .filter(|_| self.current_song_cached_till > now)
+            .and_then(|song| serde_json::to_value(song).ok())
+            .map(|song| CurrentSongSnapshot {
+                song,
+                types: self.current_song_cached_types.clone(),
+                cached_at: self.current_song_cached_at,
+                cached_till: self.current_song_cached_till,
+            });
+        let top_songs = self
+            .top_songs_cached_response
+            .iter()
+            .filter_map(|(time_range, cached)| {
+                let cached_till = self.top_songs_cached_till.get(time_range).copied()?;
+                if cached_till <= now {
+                    return None;
+                }
+
+                Some(TopSongsSnapshot {
+                    time_range: *time_range,
+                    songs: serde_json::to_value(cached.as_ref()?.items().ok()?).ok()?,
+                    cached_till,
+                })
+            })
+            .collect();
+
+        CacheSnapshot {
+            token,
+            current_song,
+            top_songs,
+        }
+    }
+
+    /// Restores the caches of an exported snapshot, returning the names of the
+    /// caches restored. Anything already expired or cached for longer than it
+    /// could have been, from a clock ahead of this one, is left out.
+    pub fn import_caches(&mut self, snapshot: CacheSnapshot) -> Vec<&'static str> {
+        let now = chrono::Utc::now().timestamp_millis();
+        let mut imported = Vec::new();
+
+        if let Some(token) = snapshot.token {
+            let now_secs = now / 1000;
+            if token.expires_at > now_secs && token.expires_at <= now_secs + TOKEN_LIFETIME {
+                self.token = token.token;
+                self.scopes = token.scopes;
+                self.auth_expires_at = token.expires_at;
+                imported.push("token");
+            } else {
+                info!(expires_at = token.expires_at, "Skipped importing token");
+            }
+        }
+
+        if let Some(snapshot) = snapshot.current_song {
+            let max_ttl = TEN_SECONDS + TRACK_CHANGE_GRACE;
+            let song = serde_json::from_value::<CurrentSong>(to_snake_case(snapshot.song));
+            match song {
+                Ok(mut song)
+                    if snapshot.cached_at <= now
+                        && snapshot.cached_till > now
+                        && snapshot.cached_till <= now + max_ttl =>
+                {
+                    song.compute_fields();
+                    self.current_song_cached_response = Some(song);
+                    self.current_song_cached_types = snapshot.types;
+                    self.current_song_cached_at = snapshot.cached_at;
+                    self.current_song_cached_till = snapshot.cached_till;
+                    self.current_song_cached_nothing_playing = false;
+                    imported.push("current_song");
+                }
+                Ok(_) => info!(
+                    cached_till = snapshot.cached_till,
+                    "Skipped importing current song"
+                ),
+                Err(err) => error!(%err, "Could not parse imported current song"),
+            }
+        }
+
+        let mut imported_top_songs = false;
+        for snapshot in snapshot.top_songs {
+            if snapshot.cached_till <= now || snapshot.cached_till > now + TEN_MINUTES {
+                info!(
+                    time_range = %snapshot.time_range,
+                    cached_till = snapshot.cached_till,
+                    "Skipped importing top songs"
+                );
+                continue;
+            }
+
+            // Read back like the Spotify response the songs were parsed from
+            let songs = to_snake_case(snapshot.songs);
+            let items = match serde_json::from_value::<Vec<Item>>(songs.clone()) {
+                Ok(items) => items,
+                Err(err) => {
+                    error!(%err, "Could not parse imported top songs");
+                    continue;
+                }
+            };
+            let cached = if self.compress_cache {
+                let body = serde_json::json!({ "items": songs }).to_string();
+                CachedTopSongs::Compressed(cache::compress(body.as_bytes()))
+            } else {
+                CachedTopSongs::Parsed(items)
+            };
+            self.top_songs_cached_response
+                .insert(snapshot.time_range, Some(cached));
+            self.top_songs_cached_till
+                .insert(snapshot.time_range, snapshot.cached_till);
+            imported_top_songs = true;
+        }
+        if imported_top_songs {
+            imported.push("top_songs");
+        }
+
+        imported
+    }
+
+    /// Sends a request to the Spotify api with the current token. When Spotify
+    /// rejects the token before it should have expired, it's refreshed and the
+    /// request retried once.
+    async fn send(
+        &mut self,
+        request: reqwest::RequestBuilder,
+    ) -> Result<reqwest::Response, reqwest::Error> {
+        let retry = request.try_clone();
+        let response = {
+            let _permit = self.scheduler.acquire().await;
+            request.bearer_auth(&self.token).send().await?
+        };
+
+        let Some(retry) = retry.filter(|_| response.status() == reqwest::StatusCode::UNAUTHORIZED)
+        else {
+            return Ok(response);
+        };
+        info!("Spotify rejected the token, refreshing it");
+        if self.get_token().await.is_err() {
+            return Ok(response);
+        }
+
+        let _permit = self.scheduler.acquire().await;
+        retry.bearer_auth(&self.token).send().await
+    }
+
+    /// Authenticated GET against the Spotify api, parsing the body as `T`
+    async fn get_json<T: DeserializeOwned>(&mut self, url: &str) -> Result<T, ()> {
+        if chrono::Utc::now().timestamp() > self.auth_expires_at && self.get_token().await.is_err()
+        {
+            record_spotify_error(
+                reqwest::StatusCode::BAD_GATEWAY,
+                r#"{"error":{"status":502,"message":"Bad gateway"}}"#,
+            );
+            return Err(());
+        }
+
+        let client = reqwest::Client::new();
+        let res = self.send(client.get(url)).await;
+
+        if let Err(error) = &res {
+            error!(%error, url, "Could not reach spotify");
+            return Err(());
+        }
+
+        let response = res.unwrap();
+        if !response.status().is_success() {
+            error!(?response, "Spotify request failed");
+            record_failed_response(response).await;
+            return Err(());
+        }
+
+        let body = response.text().await;
+        if let Err(err) = &body {
+            error!(%err, "Could not decode spotify body");
+            return Err(());
+        }
+
+        // A `204` has no body, which reads as `None` for optional responses
+        let body = body.unwrap();
+        let json = serde_json::from_str(if body.is_empty() { "null" } else { &body });
+        if let Err(err) = &json {
+            error!(%err, url, "Could not parse spotify response to json");
+            return Err(());
+        }
+
+        Ok(json.unwrap())
+    }
+
+    /// Follows the `next` links of a paginated endpoint until `limit` items were
+    /// collected or `max_pages` pages were fetched. `page` picks the page out of
+    /// the response, as most endpoints nest it.
+    async fn fetch_all_pages<T, R: DeserializeOwned>(
+        &mut self,
+        url: String,
+        limit: usize,
+        page: impl Fn(R) -> Page<T>,
+    ) -> Result<Vec<T>, ()> {
+        let mut items = Vec::new();
+        let mut pages = 0;
+        let mut url = Some(url);
+        while let Some(next) = url {
+            let current = page(self.get_json(&next).await?);
+            items.extend(current.items);
+            pages += 1;
+            url = current
+                .next
+                .filter(|_| items.len() < limit && pages < self.max_pages);
+        }
+
+        items.truncate(limit);
+        Ok(items)
+    }
+
+    /// Makes the cheapest authenticated call Spotify offers
+    async fn ping(&mut self) -> bool {
+        if chrono::Utc::now().timestamp() > self.auth_expires_at && self.get_token().await.is_err()
+        {
+            return false;
+        }
+
+        let client = reqwest::Client::new();
+        let res = self.send(client.get(format!("{:}/me", self.api_url))).await;
+
+        match res {
+            Ok(response) if response.status().is_success() => true,
+            Ok(response) => {
+                error!(?response, "Spotify health check failed");
+                false
+            }
+            Err(error) => {
+                error!(%error, "Could not reach spotify");
+                false
+            }
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct CacheStatus {
+    current_song: CacheExpiry,
+    top_songs: CacheExpiry,
+    token: CacheExpiry,
+}
+
+/// Songs are kept in the JSON the routes serve and read back like a Spotify
+/// response. The fields only served, the market count, fallback art and ads,
+/// are read back as well.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct CacheSnapshot {
+    token: Option<TokenSnapshot>,
+    current_song: Option<CurrentSongSnapshot>,
+    #[serde(default)]
+    top_songs: Vec<TopSongsSnapshot>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct TokenSnapshot {
+    token: String,
+    scopes: Vec<String>,
+    /// Unix timestamp in seconds
+    expires_at: i64,
+}
+
+/// Times are unix timestamps in milliseconds
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct CurrentSongSnapshot {
+    song: Value,
+    types: String,
+    cached_at: i64,
+    cached_till: i64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct TopSongsSnapshot {
+    time_range: TimeRange,
+    songs: Value,
+    cached_till: i64,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct CacheExpiry {
+    /// Negative once expired, `None` if it was never cached
+    expires_in_ms: Option<i64>,
+    expires_in: String,
+}
+
+impl CacheExpiry {
+    fn new(cached_till: i64, now: i64) -> Self {
+        if cached_till == 0 {
+            return Self {
+                expires_in_ms: None,
+                expires_in: "never cached".into(),
+            };
+        }
+
+        let expires_in_ms = cached_till - now;
+        let seconds = expires_in_ms.abs() / 1000;
+        let duration = match seconds {
+            0..=59 => format!("{:}s", seconds),
+            60..=3599 => format!("{:}m {:}s", seconds / 60, seconds % 60),
+            _ => format!("{:}h {:}m", seconds / 3600, seconds % 3600 / 60),
+        };
+
+        Self {
+            expires_in_ms: Some(expires_in_ms),
+            expires_in: if expires_in_ms > 0 {
+                format!("in {:}", duration)
+            } else {
+                format!("expired {:} ago", duration)
+            },
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct SpotifyHealth {
+    pub reachable: bool,
+    pub latency_ms: u64,
+}
+
+/// Reads the error body of a failed Spotify response, when the request being
+/// served asked for it
+async fn record_failed_response(response: reqwest::Response) {
+    if SPOTIFY_ERROR.try_with(|_| ()).is_err() {
+        return;
+    }
+
+    let status = response.status();
+    let body = response.text().await.unwrap_or_default();
+    record_spotify_error(status, &body);
+}
+
+fn record_spotify_error(status: reqwest::StatusCode, body: &str) {
+    // Spotify answers `{"error": {"status", "message"}}`, anything else is kept as is
+    let error = serde_json::from_str::<SpotifyErrorResponse>(body)
+        .map(|response| response.error)
+        .unwrap_or_else(|_| SpotifyError {
+            status: status.as_u16(),
+            message: body.to_string(),
+        });
+    let _ = SPOTIFY_ERROR.try_with(|last_error| *last_error.borrow_mut() = Some(error));
+}
+
+#[derive(Deserialize, Debug)]
+struct SpotifyErrorResponse {
+    error: SpotifyError,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SpotifyError {
+    pub status: u16,
+    pub message: String,
+}
+
+#[derive(Debug)]
+pub enum SpotError {
+    NotFound,
+    Forbidden,
+    RefreshTokenRevoked,
+    Unknown,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all(serialize = "camelCase"))]
+struct AuthResponse {
+    access_token: String,
+    expires_in: i64,
+    // Space separated
+    #[serde(default)]
+    scope: String,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct TokenScopes {
+    pub granted: Vec<String>,
+    /// Scopes some endpoint needs but the refresh token wasn't granted
+    pub missing: Vec<&'static str>,
+}
+
+#[derive(Debug, Deserialize)]
+struct AuthErrorResponse {
+    error: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all(serialize = "camelCase"))]
+pub struct CurrentSong {
+    progress_ms: i64,
+    /// `progress_ms` relative to the duration, `0` to `1`
+    #[serde(skip_deserializing)]
+    progress_percent: f64,
+    timestamp: i64,
+    #[serde(skip_deserializing, serialize_with = "serialize_rfc3339")]
+    timestamp_iso: i64,
+    pub item: PlayingItem,
+    pub is_playing: bool,
+    #[serde(default)]
+    context: Option<Context>,
+    /// Best-effort, Spotify doesn't say whether autoplay picked the track
+    #[serde(skip_deserializing)]
+    autoplay: bool,
+    // `track`, `episode`, `ad` or `unknown`
+    #[serde(default, skip_serializing)]
+    currently_playing_type: String,
+    /// An ad is playing, `item` is `null` then. Only read back from songs
+    /// served before, like an imported cache's, which lack the type.
+    #[serde(default)]
+    is_ad: bool,
+}
+
+impl CurrentSong {
+    pub fn duration_ms(&self) -> i64 {
+        self.item.duration_ms()
+    }
+
+    /// Time left until the song ends, from the extrapolated progress
+    pub fn remaining_ms(&self) -> i64 {
+        (self.duration_ms() - self.progress_ms).max(0)
+    }
+
+    /// Fills in the fields derived from the ones Spotify sends
+    fn compute_fields(&mut self) {
+        self.timestamp_iso = self.timestamp;
+        self.set_progress_ms(self.progress_ms);
+        self.autoplay = self
+            .context
+            .as_ref()
+            .is_some_and(|context| context.is_autoplay());
+        self.is_ad |= self.currently_playing_type == "ad";
+    }
+
+    /// Keeps the progress within the song, extrapolating it past the end of
+    /// the song would otherwise show more than 100%
+    fn set_progress_ms(&mut self, progress_ms: i64) {
+        let duration_ms = self.duration_ms();
+        self.progress_ms = progress_ms.clamp(0, duration_ms.max(0));
+        self.progress_percent = if duration_ms > 0 {
+            self.progress_ms as f64 / duration_ms as f64
+        } else {
+            0.0
+        };
+    }
+
+    pub fn image(&self, size: ImageSize) -> Option<&Image> {
+        let mut images = self.item.images().iter().collect::<Vec<&Image>>();
+        images.sort_by_key(|image| image.width.unwrap_or(0));
+        match size {
+            ImageSize::Small => images.first().copied(),
+            ImageSize::Medium => images.get(images.len() / 2).copied(),
+            ImageSize::Large => images.last().copied(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Context {
+    #[serde(rename = "type")]
+    context_type: String,
+    uri: String,
+}
+
+impl Context {
+    /// Once the queued context ran out autoplay continues from a generated station
+    fn is_autoplay(&self) -> bool {
+        self.uri.starts_with("spotify:station:")
+    }
+}
+
+/// Whether `id` is a Spotify id, 22 base62 characters. Ids from a route path
+/// are checked before going into a url, anything else could point the request
+/// at another endpoint.
+pub fn is_spotify_id(id: &str) -> bool {
+    id.len() == 22 && id.bytes().all(|byte| byte.is_ascii_alphanumeric())
+}
+
+/// Adds the `device_id` param when there is one. Passing the optional pair to
+/// `query` directly fails the request, it only serializes sequences of pairs.
+fn with_device_id(
+    request: reqwest::RequestBuilder,
+    device_id: Option<String>,
+) -> reqwest::RequestBuilder {
+    match device_id {
+        Some(device_id) => request.query(&[("device_id", device_id)]),
+        None => request,
+    }
+}
+
+/// Near the end of a track the cache expires once, right after the predicted
+/// track change, instead of refetching with sub-second TTLs
+fn current_song_ttl(remaining_ms: i64) -> i64 {
+    if remaining_ms < TEN_SECONDS {
+        return (remaining_ms + TRACK_CHANGE_GRACE).max(MIN_CURRENT_SONG_TTL);
+    }
+
+    TEN_SECONDS
+}
+
+/// Counts the values of an array without keeping them, or reads back a count
+/// served before, like the `availableMarketsCount` of an imported cache
+fn deserialize_count<'de, D>(deserializer: D) -> Result<Option<usize>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    struct CountVisitor;
+
+    impl<'de> serde::de::Visitor<'de> for CountVisitor {
+        type Value = Option<usize>;
+
+        fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+            formatter.write_str("an array or a count")
+        }
+
+        fn visit_none<E>(self) -> Result<Self::Value, E> {
+            Ok(None)
+        }
+
+        fn visit_unit<E>(self) -> Result<Self::Value, E> {
+            Ok(None)
+        }
+
+        fn visit_some<D>(self, deserializer: D) -> Result<Self::Value, D::Error>
+        where
+            D: serde::Deserializer<'de>,
+        {
+            deserializer.deserialize_any(self)
+        }
+
+        fn visit_u64<E>(self, count: u64) -> Result<Self::Value, E> {
+            Ok(Some(count as usize))
+        }
+
+        fn visit_seq<A>(self, mut values: A) -> Result<Self::Value, A::Error>
+        where
+            A: serde::de::SeqAccess<'de>,
+        {
+            let mut count = 0;
+            while values.next_element::<serde::de::IgnoredAny>()?.is_some() {
+                count += 1;
+            }
+            Ok(Some(count))
+        }
+    }
+
+    deserializer.deserialize_option(CountVisitor)
+}
+
+/// Reads `null` as the default, for fields Spotify sometimes leaves out or
+/// sends as `null` that aren't worth failing the whole response over
+fn deserialize_or_default<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    T: Default + Deserialize<'de>,
+{
+    Ok(Option::<T>::deserialize(deserializer)?.unwrap_or_default())
+}
+
+/// Serializes epoch millis as an RFC3339 string
+pub fn serialize_rfc3339<S>(millis: &i64, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: serde::Serializer,
+{
+    match chrono::DateTime::from_timestamp_millis(*millis) {
+        Some(date_time) => serializer
+            .serialize_str(&date_time.to_rfc3339_opts(chrono::SecondsFormat::Millis, true)),
+        None => serializer.serialize_none(),
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum PlayingItem {
+    Track(Item),
+    Episode(Episode),
+    /// Spotify sends a `null` item during ads on free accounts
+    Missing,
+}
+
+impl PlayingItem {
+    pub fn duration_ms(&self) -> i64 {
+        match self {
+            PlayingItem::Track(item) => item.duration_ms,
+            PlayingItem::Episode(episode) => episode.duration_ms,
+            PlayingItem::Missing => 0,
+        }
+    }
+
+    pub fn images(&self) -> &[Image] {
+        match self {
+            PlayingItem::Track(item) => &item.album.images,
+            PlayingItem::Episode(episode) => &episode.images,
+            PlayingItem::Missing => &[],
+        }
+    }
+
+    pub fn name(&self) -> Option<&str> {
+        match self {
+            PlayingItem::Track(item) => Some(&item.name),
+            PlayingItem::Episode(episode) => Some(&episode.name),
+            PlayingItem::Missing => None,
+        }
+    }
+
+    /// The artists of a track or the show of an episode
+    pub fn byline(&self) -> Option<String> {
+        match self {
+            PlayingItem::Track(item) => Some(
+                item.artists
+                    .iter()
+                    .map(|artist| artist.name.as_str())
+                    .collect::<Vec<_>>()
+                    .join(", "),
+            ),
+            PlayingItem::Episode(episode) => Some(episode.show.name.clone()),
+            PlayingItem::Missing => None,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Item {
+    name: String,
+    duration_ms: i64,
+    preview_url: Option<String>,
+    album: Album,
+    #[serde(default, deserialize_with = "deserialize_or_default")]
+    artists: Vec<Artist>,
+    #[serde(default, deserialize_with = "deserialize_or_default")]
+    external_urls: ExternalUrls,
+    // Local files have no id and a `spotify:local:` uri
+    pub id: Option<String>,
+    pub uri: Option<String>,
+    #[serde(default)]
+    is_local: bool,
+    // Only set when the request named a market, `false` for tracks unavailable there
+    is_playable: Option<bool>,
+    // The markets themselves are hundreds of country codes, only their count is kept
+    #[serde(
+        default,
+        rename(deserialize = "available_markets"),
+        alias = "available_markets_count",
+        deserialize_with = "deserialize_count"
+    )]
+    available_markets_count: Option<usize>,
+    /// The track originally asked for when Spotify relinked it for the market
+    linked_from: Option<LinkedTrack>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LinkedTrack {
+    id: String,
+    uri: String,
+}
+
+impl Item {
+    /// Identifies the track, local files have neither id nor a unique uri
+    pub fn key(&self) -> &str {
+        self.control_id().unwrap_or(&self.name)
+    }
+
+    /// Id to save or queue the track with. A relinked track plays under another
+    /// id, but the library and queue refer to the original one.
+    pub fn control_id(&self) -> Option<&str> {
+        match &self.linked_from {
+            Some(linked_from) => Some(&linked_from.id),
+            None => self.id.as_deref(),
+        }
+    }
+
+    fn preview(&self) -> Option<Preview<'_>> {
+        Some(Preview {
+            url: self.preview_url.as_deref()?,
+            proxy_url: self.id.as_ref().map(|id| {
+                format!(
+                    "{:}/preview/{:}",
+                    PATH_PREFIX.get().map_or("", String::as_str),
+                    id
+                )
+            }),
+        })
+    }
+}
+
+impl Serialize for Item {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        let mut item = serializer.serialize_struct("Item", 13)?;
+        item.serialize_field("name", &self.name)?;
+        item.serialize_field("durationMs", &self.duration_ms)?;
+        item.serialize_field("previewUrl", &self.preview_url)?;
+        item.serialize_field("preview", &self.preview())?;
+        item.serialize_field("album", &self.album)?;
+        item.serialize_field("artists", &self.artists)?;
+        item.serialize_field("externalUrls", &self.external_urls)?;
+        item.serialize_field("id", &self.id)?;
+        item.serialize_field("uri", &self.uri)?;
+        item.serialize_field("isLocal", &self.is_local)?;
+        item.serialize_field("isPlayable", &self.is_playable)?;
+        item.serialize_field("availableMarketsCount", &self.available_markets_count)?;
+        item.serialize_field("linkedFrom", &self.linked_from)?;
+        item.end()
+    }
+}
+
+/// The 30 second preview, directly from Spotify or proxied through `/preview/:id`
+/// for clients running into CORS
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct Preview<'a> {
+    url: &'a str,
+    proxy_url: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all(serialize = "camelCase"))]
+pub struct Episode {
+    name: String,
+    duration_ms: i64,
+    #[serde(default, deserialize_with = "deserialize_or_default")]
+    images: Vec<Image>,
+    show: Show,
+    #[serde(default, deserialize_with = "deserialize_or_default")]
+    external_urls: ExternalUrls,
+    /// Where the account left off, only sent with the
+    /// `user-read-playback-position` scope
+    #[serde(default)]
+    resume_point: Option<ResumePoint>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all(serialize = "camelCase"))]
+pub struct ResumePoint {
+    fully_played: bool,
+    resume_position_ms: i64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all(serialize = "camelCase"))]
+pub struct Show {
+    name: String,
+    publisher: String,
+    #[serde(default, deserialize_with = "deserialize_or_default")]
+    images: Vec<Image>,
+    #[serde(default, deserialize_with = "deserialize_or_default")]
+    external_urls: ExternalUrls,
+    uri: String,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(from = "AlbumFields")]
+pub struct Album {
+    album_type: Option<String>,
+    artists: Vec<Artist>,
+    external_urls: ExternalUrls,
+    images: Vec<Image>,
+    name: String,
+    uri: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct AlbumFields {
+    album_type: Option<String>,
+    #[serde(default, deserialize_with = "deserialize_or_default")]
+    artists: Vec<Artist>,
+    #[serde(default, deserialize_with = "deserialize_or_default")]
+    external_urls: ExternalUrls,
+    #[serde(default, deserialize_with = "deserialize_or_default")]
+    images: Vec<Image>,
+    name: String,
+    uri: Option<String>,
+    // Only in albums served before, like an imported cache's
+    #[serde(default)]
+    is_fallback_image: bool,
+}
+
+impl From<AlbumFields> for Album {
+    /// A served fallback image isn't the album's art, it's added again when
+    /// serving the album
+    fn from(fields: AlbumFields) -> Self {
+        Self {
+            album_type: fields.album_type,
+            artists: fields.artists,
+            external_urls: fields.external_urls,
+            images: if fields.is_fallback_image {
+                Vec::new()
+            } else {
+                fields.images
+            },
+            name: fields.name,
+            uri: fields.uri,
+        }
+    }
+}
+
+impl Album {
+    /// Spotify doesn't guarantee the order of `images`, so pick the narrowest one
+    pub fn thumbnail(&self) -> Option<&Image> {
+        self.images
+            .iter()
+            .min_by_key(|image| image.width.unwrap_or(i64::MAX))
+    }
+}
+
+impl Serialize for Album {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        let fallback = match FALLBACK_IMAGE_URL.get() {
+            Some(url) if self.images.is_empty() => Some(vec![Image {
+                height: None,
+                url: url.clone(),
+                width: None,
+            }]),
+            _ => None,
+        };
+        let images = fallback.as_ref().unwrap_or(&self.images);
+
+        let mut album = serializer.serialize_struct("Album", 8)?;
+        album.serialize_field("albumType", &self.album_type)?;
+        album.serialize_field("artists", &self.artists)?;
+        album.serialize_field("externalUrls", &self.external_urls)?;
+        album.serialize_field("images", images)?;
+        album.serialize_field("isFallbackImage", &fallback.is_some())?;
+        album.serialize_field(
+            "thumbnailUrl",
+            &self.thumbnail().or(images.first()).map(|image| &image.url),
+        )?;
+        album.serialize_field("name", &self.name)?;
+        album.serialize_field("uri", &self.uri)?;
+        album.end()
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all(serialize = "camelCase"))]
+pub struct Artist {
+    #[serde(default, deserialize_with = "deserialize_or_default")]
+    external_urls: ExternalUrls,
+    href: Option<String>,
+    name: String,
+    uri: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all(serialize = "camelCase"))]
+pub struct FullArtist {
+    id: String,
+    name: String,
+    genres: Vec<String>,
+    images: Vec<Image>,
+    popularity: i64,
+    followers: Followers,
+    external_urls: ExternalUrls,
+    uri: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct Followers {
+    total: i64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct Artists {
+    artists: Vec<Option<FullArtist>>,
+}
+
+/// Audio attributes recommendations can be tuned with through `min_`, `max_`
+/// and `target_` params, with the range Spotify accepts for each
+pub const TUNABLE_ATTRIBUTES: [(&str, f64, f64); 9] = [
+    ("acousticness", 0.0, 1.0),
+    ("danceability", 0.0, 1.0),
+    ("energy", 0.0, 1.0),
+    ("instrumentalness", 0.0, 1.0),
+    ("liveness", 0.0, 1.0),
+    ("speechiness", 0.0, 1.0),
+    ("valence", 0.0, 1.0),
+    ("popularity", 0.0, 100.0),
+    ("tempo", 0.0, 300.0),
+];
+
+#[derive(Deserialize, Debug)]
+struct Recommendations {
+    tracks: Vec<Item>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all(serialize = "camelCase"))]
+pub struct Profile {
+    pub id: String,
+    pub display_name: Option<String>,
+    pub country: Option<String>,
+    pub product: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct Page<T> {
+    items: Vec<T>,
+    next: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct NewReleases {
+    albums: Page<Album>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all(serialize = "camelCase"))]
+pub struct Device {
+    pub id: Option<String>,
+    pub name: String,
+    #[serde(rename(deserialize = "type"))]
+    device_type: String,
+    pub is_active: bool,
+    volume_percent: Option<i64>,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct TopSongsComparison {
+    short: Vec<Item>,
+    long: Vec<Item>,
+    /// Ids of the short term songs missing from the long term ones
+    new_in_short: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SyncedLyrics {
+    lines: Vec<LyricsLine>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all(serialize = "camelCase"))]
+pub struct LyricsLine {
+    /// When the line starts, compare with the current song's `progressMs`
+    #[serde(alias = "timeMs")]
+    time_ms: i64,
+    text: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct CanvasResponse {
+    #[serde(alias = "canvasUrl")]
+    canvas_url: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct Queue {
+    queue: Vec<PlayingItem>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all(serialize = "camelCase"))]
+pub struct PlaybackState {
+    pub device: Device,
+    is_playing: bool,
+    shuffle_state: bool,
+    pub repeat_state: RepeatMode,
+    /// Playback moved to another device since the state was last returned
+    #[serde(skip_deserializing)]
+    pub device_changed: bool,
+}
+
+#[derive(Deserialize, Debug)]
+struct Devices {
+    devices: Vec<Device>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all(serialize = "camelCase"))]
+pub struct Playlist {
+    id: String,
+    name: String,
+    description: Option<String>,
+    // `null` for playlists without tracks
+    #[serde(default, deserialize_with = "deserialize_or_default")]
+    images: Vec<Image>,
+    owner: PlaylistOwner,
+    tracks: PlaylistTracks,
+    #[serde(default, deserialize_with = "deserialize_or_default")]
+    external_urls: ExternalUrls,
+    uri: String,
+    /// Changes with every change to the playlist
+    #[serde(default, skip_serializing)]
+    snapshot_id: String,
+}
+
+/// A playlist item trimmed to the track's id
+#[derive(Deserialize, Debug)]
+struct PlaylistTrackId {
+    track: Option<TrackId>,
+}
+
+#[derive(Deserialize, Debug)]
+struct TrackId {
+    id: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all(serialize = "camelCase"))]
+struct PlaylistOwner {
+    id: String,
+    display_name: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct PlaylistTracks {
+    total: usize,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct FeaturedPlaylists {
+    message: Option<String>,
+    playlists: Vec<Playlist>,
+}
+
+#[derive(Deserialize, Debug)]
+struct FeaturedPlaylistsResponse {
+    message: Option<String>,
+    playlists: Page<Playlist>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AudioAnalysis {
+    sections: Vec<Section>,
+    beats: Vec<Beat>,
+}
+
+/// Times are in seconds from the start of the track
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Section {
+    start: f64,
+    duration: f64,
+    loudness: f64,
+    tempo: f64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Beat {
+    start: f64,
+    duration: f64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct AudioFeaturesList {
+    audio_features: Vec<Option<AudioFeatures>>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AudioFeatures {
+    energy: f64,
+    valence: f64,
+    danceability: f64,
+    tempo: f64,
+    acousticness: f64,
+}
+
+/// Averages over the top songs, `0` to `1` except for the tempo in bpm
+#[derive(Serialize, Debug, Clone)]
+pub struct Taste {
+    energy: Option<f64>,
+    valence: Option<f64>,
+    danceability: Option<f64>,
+    tempo: Option<f64>,
+    acousticness: Option<f64>,
+    /// Number of top songs averaged over
+    tracks: usize,
+}
+
+/// A track in the connected account's liked songs
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all(serialize = "camelCase"))]
+pub struct LikedTrack {
+    added_at: String,
+    track: Item,
+}
+
+/// Unix ms cursor paging through the recently played tracks
+#[derive(Debug, Clone, Copy)]
+pub enum PlayedCursor {
+    Before(i64),
+    After(i64),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all(serialize = "camelCase"))]
+pub struct RecentlyPlayed {
+    items: Vec<PlayedTrack>,
+    /// `before` pages back to older plays, `null` once there are none
+    cursors: Option<PlayedCursors>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all(serialize = "camelCase"))]
+pub struct PlayedTrack {
+    played_at: String,
+    track: Item,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PlayedCursors {
+    before: Option<String>,
+    after: Option<String>,
+}
+
+/// An album in the connected account's library
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all(serialize = "camelCase"))]
+pub struct SavedAlbum {
+    added_at: String,
+    album: Album,
+}
+
+/// A podcast in the connected account's library
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all(serialize = "camelCase"))]
+pub struct SavedShow {
+    added_at: String,
+    show: FullShow,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all(serialize = "camelCase"))]
+pub struct FullShow {
+    id: String,
+    name: String,
+    publisher: String,
+    description: String,
+    #[serde(default, deserialize_with = "deserialize_or_default")]
+    images: Vec<Image>,
+    total_episodes: Option<i64>,
+    #[serde(default, deserialize_with = "deserialize_or_default")]
+    external_urls: ExternalUrls,
+    uri: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct GenreSeeds {
+    genres: Vec<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct FollowedArtists {
+    artists: Page<FullArtist>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+struct ExternalUrls {
+    // Empty for local files
+    spotify: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Image {
+    // Playlist images come without dimensions
+    height: Option<i64>,
+    pub url: String,
+    width: Option<i64>,
+}
+
+impl Image {
+    /// Image urls are content addressed, the last path segment identifies the image
+    pub fn id(&self) -> &str {
+        self.url.rsplit('/').next().unwrap_or(&self.url)
+    }
+}
+
+#[derive(Deserialize, Debug, Default, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum ImageSize {
+    Small,
+    Medium,
+    #[default]
+    Large,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TopItems {
+    items: Vec<Item>,
+}
+
+/// Cached top songs, or with `COMPRESS_CACHE` the deflated Spotify response
+/// they're parsed from again on every hit. The parsed songs can't be stored
+/// compressed, they serialize to a different shape than Spotify's.
+pub enum CachedTopSongs {
+    Parsed(Vec<Item>),
+    Compressed(Vec<u8>),
+}
+
+impl CachedTopSongs {
+    fn items(&self) -> Result<Vec<Item>, ()> {
+        match self {
+            CachedTopSongs::Parsed(items) => Ok(items.clone()),
+            CachedTopSongs::Compressed(compressed) => {
+                let body = cache::decompress(compressed).map_err(|err| {
+                    error!(%err, "Could not decompress cached top songs");
+                })?;
+                serde_json::from_str::<TopItems>(&body)
+                    .map(|json| json.items)
+                    .map_err(|err| {
+                        error!(%err, "Could not parse cached top songs");
+                    })
+            }
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Display, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum RepeatMode {
+    #[strum(serialize = "off")]
+    Off,
+    #[strum(serialize = "context")]
+    Context,
+    #[strum(serialize = "track")]
+    Track,
+}
+
+impl RepeatMode {
+    /// The mode after this one in the cycle a single repeat button steps through
+    pub fn next(self) -> Self {
+        match self {
+            RepeatMode::Off => RepeatMode::Context,
+            RepeatMode::Context => RepeatMode::Track,
+            RepeatMode::Track => RepeatMode::Off,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Display, Clone)]
+#[serde(rename_all = "lowercase")]
+pub enum MediaState {
+    #[strum(serialize = "play")]
+    Play,
+    #[strum(serialize = "pause")]
+    Pause,
+    #[strum(serialize = "next")]
+    Next,
+    #[strum(serialize = "previous")]
+    Previous,
+}
+
+#[derive(Serialize, Deserialize, Debug, Display, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum TimeRange {
+    #[strum(serialize = "short_term")]
+    Short,
+    #[strum(serialize = "medium_term")]
+    Medium,
+    #[strum(serialize = "long_term")]
+    Long,
+}
+
+#[derive(Debug, Display, EnumString, Clone, Copy, PartialEq)]
+pub enum AdditionalType {
+    #[strum(serialize = "track")]
+    Track,
+    #[strum(serialize = "episode")]
+    Episode,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::TcpListener,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use axum::{
+        http::StatusCode,
+        response::IntoResponse,
+        routing::{get, post, put},
+        Json, Router,
+    };
+
+    use super::*;
+
+    /// Serves the routes `routes` builds from the server's own url, which
+    /// pagination links point at, returning the url
+    fn mock_server(routes: impl FnOnce(&str) -> Router) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{:}", listener.local_addr().unwrap());
+        let server = axum::Server::from_tcp(listener)
+            .unwrap()
+            .serve(routes(&url).into_make_service());
+        tokio::spawn(server);
+        url
+    }
+
+    /// Client against a mock server, with a token that's valid for an hour
+    fn mock_spot(url: &str) -> Spot {
+        let config = Config::for_tests();
+        let mut spot = Spot::new(
+            &config,
+            Scheduler::new(config.max_concurrent_spotify_requests),
+            History::new(0),
+            IdleBackoff::new(Duration::from_secs(60)),
+        );
+        spot.api_url = url.into();
+        spot.accounts_url = url.into();
+        spot.token = "token".into();
+        spot.auth_expires_at = chrono::Utc::now().timestamp() + 3600;
+        spot
+    }
+
+    /// Pages of `[1, 2]` then `[3]`, counting the requests for them
+    fn two_pages(requests: Arc<AtomicUsize>) -> impl FnOnce(&str) -> Router {
+        move |url| {
+            let next = format!("{:}/numbers?page=2", url);
+            Router::new().route(
+                "/numbers",
+                get(move |query: axum::extract::RawQuery| {
+                    requests.fetch_add(1, Ordering::SeqCst);
+                    let page = match query.0.as_deref() {
+                        Some("page=2") => serde_json::json!({ "items": [3], "next": null }),
+                        _ => serde_json::json!({ "items": [1, 2], "next": next }),
+                    };
+                    async move { Json(page) }
+                }),
+            )
+        }
+    }
+
+    #[test]
+    fn top_tracks_for_a_market_keep_is_playable() {
+        let top_items: TopItems =
+            serde_json::from_str(include_str!("../reference/spotify/top-tracks-market.json"))
+                .unwrap();
+        let playable = top_items
+            .items
+            .iter()
+            .map(|item| item.is_playable)
+            .collect::<Vec<_>>();
+
+        assert_eq!(playable, vec![Some(true), Some(false)]);
+        assert_eq!(
+            serde_json::to_value(&top_items.items[1]).unwrap()["isPlayable"],
+            false
+        );
+    }
+
+    /// Plays anything, answering seeks with `status` and counting them
+    fn seek_answering(status: StatusCode, seeks: Arc<AtomicUsize>) -> impl FnOnce(&str) -> Router {
+        move |_| {
+            Router::new()
+                .route("/me/player/play", put(|| async { StatusCode::NO_CONTENT }))
+                .route(
+                    "/me/player/seek",
+                    put(move || {
+                        seeks.fetch_add(1, Ordering::SeqCst);
+                        async move { status }
+                    }),
+                )
+        }
+    }
+
+    #[tokio::test]
+    async fn play_from_retries_the_seek_while_the_device_is_missing() {
+        let seeks = Arc::new(AtomicUsize::new(0));
+        let url = mock_server(seek_answering(StatusCode::NOT_FOUND, seeks.clone()));
+        let mut spot = mock_spot(&url);
+        spot.device_wake_retries = 0;
+
+        let result = spot.play_from(None, Some(1000), None, None).await;
+
+        assert!(matches!(result, Err(SpotError::NotFound)));
+        assert_eq!(
+            seeks.load(Ordering::SeqCst),
+            PLAY_FROM_SEEK_ATTEMPTS as usize
+        );
+    }
+
+    #[tokio::test]
+    async fn play_from_gives_up_on_other_seek_failures() {
+        let seeks = Arc::new(AtomicUsize::new(0));
+        let url = mock_server(seek_answering(StatusCode::FORBIDDEN, seeks.clone()));
+        let mut spot = mock_spot(&url);
+        spot.device_wake_retries = 0;
+
+        let result = spot.play_from(None, Some(1000), None, None).await;
+
+        assert!(matches!(result, Err(SpotError::Unknown)));
+        assert_eq!(seeks.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn market_lookup_failures_are_cached() {
+        let profiles = Arc::new(AtomicUsize::new(0));
+        let requests = profiles.clone();
+        let url = mock_server(move |_| {
+            Router::new().route(
+                "/me",
+                get(move || {
+                    requests.fetch_add(1, Ordering::SeqCst);
+                    async { StatusCode::INTERNAL_SERVER_ERROR }
+                }),
+            )
+        });
+        let mut spot = mock_spot(&url);
+
+        assert_eq!(spot.market_param().await, "");
+        assert_eq!(spot.market_param().await, "");
+        assert_eq!(profiles.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn relinked_tracks_are_controlled_by_their_original_id() {
+        let current_song: CurrentSong = serde_json::from_str(include_str!(
+            "../reference/spotify/currently-playing-relinked.json"
+        ))
+        .unwrap();
+        let item = track(&current_song);
+
+        assert_eq!(item.id.as_deref(), Some("6kLCHFM39wkFjOuyPGLGeQ"));
+        assert_eq!(item.control_id(), Some("3n3Ppam7vgaVa1iaRUc9Lp"));
+        assert_eq!(item.key(), "3n3Ppam7vgaVa1iaRUc9Lp");
+    }
+
+    /// Lists `device_id` as the active device, keeping the queries of pauses
+    fn pause_recording(queries: Arc<std::sync::Mutex<Vec<String>>>) -> impl FnOnce(&str) -> Router {
+        move |_| {
+            Router::new()
+                .route(
+                    "/me/player/devices",
+                    get(|| async {
+                        Json(serde_json::json!({ "devices": [{
+                            "id": "active-device",
+                            "name": "Kitchen",
+                            "type": "Speaker",
+                            "is_active": true,
+                            "volume_percent": 50
+                        }] }))
+                    }),
+                )
+                .route(
+                    "/me/player/pause",
+                    put(move |query: axum::extract::RawQuery| {
+                        queries.lock().unwrap().push(query.0.unwrap_or_default());
+                        async { StatusCode::NO_CONTENT }
+                    }),
+                )
+        }
+    }
+
+    #[tokio::test]
+    async fn writes_target_the_active_device() {
+        let queries = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let url = mock_server(pause_recording(queries.clone()));
+        let mut spot = mock_spot(&url);
+        spot.refresh_active_device = true;
+
+        let result = spot.update_player_state(MediaState::Pause).await;
+
+        assert!(result.is_ok());
+        assert_eq!(*queries.lock().unwrap(), vec!["device_id=active-device"]);
+    }
+
+    #[test]
+    fn ads_parse_without_an_item() {
+        let mut current_song: CurrentSong = serde_json::from_str(include_str!(
+            "../reference/spotify/currently-playing-ad.json"
+        ))
+        .unwrap();
+        current_song.compute_fields();
+
+        assert!(current_song.is_ad);
+        assert!(matches!(current_song.item, PlayingItem::Missing));
+        assert_eq!(current_song.duration_ms(), 0);
+        assert_eq!(current_song.progress_percent, 0.0);
+    }
+
+    /// Exports `spot`'s caches and imports them into a fresh `Spot` like the
+    /// `/cache/export` and `/cache/import` routes do
+    fn round_trip(spot: &Spot) -> Spot {
+        let snapshot = serde_json::to_string(&spot.export_caches()).unwrap();
+        let mut imported = mock_spot("http://localhost");
+        imported.import_caches(serde_json::from_str(&snapshot).unwrap());
+        imported
+    }
+
+    fn cache_current_song(spot: &mut Spot, fixture: &str) {
+        let mut current_song: CurrentSong = serde_json::from_str(fixture).unwrap();
+        current_song.compute_fields();
+        let now = chrono::Utc::now().timestamp_millis();
+        spot.current_song_cached_response = Some(current_song);
+        spot.current_song_cached_types = AdditionalType::Track.to_string();
+        spot.current_song_cached_at = now;
+        spot.current_song_cached_till = now + TEN_SECONDS;
+    }
+
+    #[test]
+    fn cache_round_trip_keeps_the_served_songs() {
+        let mut spot = mock_spot("http://localhost");
+        cache_current_song(
+            &mut spot,
+            include_str!("../reference/spotify/currently-playing-relinked.json"),
+        );
+        let top_tracks: Value = serde_json::from_str(&top_tracks_with_markets(2)).unwrap();
+        let items: Vec<Item> = serde_json::from_value(top_tracks["items"].clone()).unwrap();
+        spot.top_songs_cached_response
+            .insert(TimeRange::Short, Some(CachedTopSongs::Parsed(items)));
+        spot.top_songs_cached_till.insert(
+            TimeRange::Short,
+            chrono::Utc::now().timestamp_millis() + TEN_MINUTES,
+        );
+
+        let exported = serde_json::to_value(spot.export_caches()).unwrap();
+        let imported = round_trip(&spot);
+        let exported_again = serde_json::to_value(imported.export_caches()).unwrap();
+
+        assert_eq!(
+            exported["topSongs"][0]["songs"][0]["availableMarketsCount"],
+            185
+        );
+        assert!(exported["currentSong"]["song"]["item"].is_object());
+        assert_eq!(
+            exported_again["currentSong"]["song"],
+            exported["currentSong"]["song"]
+        );
+        assert_eq!(
+            exported_again["topSongs"][0]["songs"],
+            exported["topSongs"][0]["songs"]
+        );
+    }
+
+    #[test]
+    fn cache_round_trip_keeps_ads() {
+        let mut spot = mock_spot("http://localhost");
+        cache_current_song(
+            &mut spot,
+            include_str!("../reference/spotify/currently-playing-ad.json"),
+        );
+
+        let imported = round_trip(&spot);
+
+        assert!(imported.current_song_cached_response.unwrap().is_ad);
+    }
+
+    #[test]
+    fn spotify_ids_are_22_base62_characters() {
+        assert!(is_spotify_id("4cOdK2wGLETKBW3PvgPWqT"));
+        assert!(!is_spotify_id("4cOdK2wGLETKBW3PvgPWq"));
+        assert!(!is_spotify_id("../me/player?x=4cOdK2w"));
+        assert!(!is_spotify_id("4cOdK2wGLETKBW3PvgPW%2F"));
+    }
+
+    #[test]
+    fn served_fallback_art_is_not_read_back() {
+        let album: Album = serde_json::from_value(serde_json::json!({
+            "name": "Album",
+            "images": [{ "url": "https://example.com/fallback.png" }],
+            "is_fallback_image": true,
+        }))
+        .unwrap();
+
+        assert!(album.images.is_empty());
+    }
+
+    #[test]
+    fn missing_scopes_are_unknown_before_a_refresh() {
+        let mut spot = mock_spot("http://localhost");
+        spot.scopes = Vec::new();
+
+        assert_eq!(spot.missing_scope("/top-songs"), None);
+    }
+
+    #[test]
+    fn missing_scopes_are_the_ones_not_granted() {
+        let mut spot = mock_spot("http://localhost");
+        spot.scopes = vec!["user-read-currently-playing".into()];
+
+        assert_eq!(spot.missing_scope("/top-songs"), Some("user-top-read"));
+        assert_eq!(spot.missing_scope("/"), None);
+    }
+
+    #[test]
+    fn missing_or_null_non_critical_fields_default() {
+        let current_song: CurrentSong = serde_json::from_str(include_str!(
+            "../reference/spotify/currently-playing-missing-fields.json"
+        ))
+        .unwrap();
+        let item = track(&current_song);
+
+        assert!(item.artists.is_empty());
+        assert_eq!(item.external_urls.spotify, None);
+        assert!(item.album.images.is_empty());
+        assert!(item.album.artists.is_empty());
+        assert_eq!(current_song.item.byline().as_deref(), Some(""));
+    }
+
+    /// Progress of the current song cached five seconds ago, as a cache hit reports it
+    async fn progress_after_five_seconds(is_playing: bool) -> i64 {
+        let mut current_song: CurrentSong = serde_json::from_str(include_str!(
+            "../reference/spotify/currently-playing-local-file.json"
+        ))
+        .unwrap();
+        current_song.is_playing = is_playing;
+        current_song.compute_fields();
+
+        let mut spot = mock_spot("http://localhost");
+        let now = chrono::Utc::now().timestamp_millis();
+        spot.current_song_cached_types = AdditionalType::Track.to_string();
+        spot.current_song_cached_response = Some(current_song);
+        spot.current_song_cached_at = now - 5000;
+        spot.current_song_cached_till = now + TEN_SECONDS;
+
+        let current_song = spot
+            .get_current_song(&[AdditionalType::Track])
+            .await
+            .unwrap()
+            .unwrap();
+        current_song.progress_ms
+    }
+
+    #[tokio::test]
+    async fn paused_songs_keep_their_cached_progress() {
+        assert_eq!(progress_after_five_seconds(false).await, 30500);
+    }
+
+    #[tokio::test]
+    async fn playing_songs_extrapolate_their_cached_progress() {
+        assert!(progress_after_five_seconds(true).await >= 35500);
+    }
+
+    /// Playing a track with a lyrics provider answering `lyrics` for it
+    fn lyrics_for(lyrics: String) -> Spot {
+        let url = mock_server(move |_| {
+            Router::new().route(
+                "/lyrics/3n3Ppam7vgaVa1iaRUc9Lp",
+                get(move || async move { lyrics }),
+            )
+        });
+        let mut spot = mock_spot(&url);
+        spot.synced_lyrics_provider_url = Some(format!("{:}/lyrics/{{track_id}}", url));
+
+        let mut current_song: CurrentSong = serde_json::from_str(include_str!(
+            "../reference/spotify/currently-playing-missing-fields.json"
+        ))
+        .unwrap();
+        current_song.compute_fields();
+        let now = chrono::Utc::now().timestamp_millis();
+        spot.current_song_cached_types = AdditionalType::Track.to_string();
+        spot.current_song_cached_response = Some(current_song);
+        spot.current_song_cached_at = now;
+        spot.current_song_cached_till = now + TEN_SECONDS;
+        spot
+    }
+
+    #[tokio::test]
+    async fn synced_lyrics_are_read_from_the_provider() {
+        let lyrics = r#"{"lines":[{"timeMs":1000,"text":"First line"}]}"#;
+        let mut spot = lyrics_for(lyrics.into());
+
+        let lyrics = spot.get_synced_lyrics().await.unwrap().unwrap();
+
+        assert_eq!(lyrics.lines[0].text, "First line");
+    }
+
+    #[tokio::test]
+    async fn oversized_synced_lyrics_are_rejected() {
+        let mut spot = lyrics_for(" ".repeat(MAX_SYNCED_LYRICS_BYTES + 1));
+
+        assert!(spot.get_synced_lyrics().await.is_err());
+    }
+
+    /// Keeps the queries of seeks and shuffles
+    fn player_writes_recording(
+        queries: Arc<std::sync::Mutex<Vec<String>>>,
+    ) -> impl FnOnce(&str) -> Router {
+        move |_| {
+            let record = move |query: axum::extract::RawQuery| {
+                queries.lock().unwrap().push(query.0.unwrap_or_default());
+                async { StatusCode::NO_CONTENT }
+            };
+            Router::new()
+                .route("/me/player/seek", put(record.clone()))
+                .route("/me/player/shuffle", put(record))
+        }
+    }
+
+    #[tokio::test]
+    async fn seek_and_shuffle_forward_the_device_id() {
+        let queries = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let url = mock_server(player_writes_recording(queries.clone()));
+        let mut spot = mock_spot(&url);
+
+        assert!(spot.seek(1000, Some("kitchen")).await.is_ok());
+        assert!(spot.set_shuffle(true, Some("kitchen")).await.is_ok());
+
+        assert_eq!(
+            *queries.lock().unwrap(),
+            vec![
+                "position_ms=1000&device_id=kitchen",
+                "state=true&device_id=kitchen"
+            ]
+        );
+    }
+
+    /// Top tracks response like Spotify's without a market, every track and
+    /// album listing the ~185 markets it's available in
+    fn top_tracks_with_markets(tracks: usize) -> String {
+        let markets = ('A'..='Z')
+            .flat_map(|first| ('A'..='Z').map(move |second| format!("{:}{:}", first, second)))
+            .take(185)
+            .collect::<Vec<_>>();
+        let mut top_tracks: Value =
+            serde_json::from_str(include_str!("../reference/spotify/top-tracks-market.json"))
+                .unwrap();
+        let mut track = top_tracks["items"][0].take();
+        track["available_markets"] = serde_json::json!(markets);
+        track["album"]["available_markets"] = serde_json::json!(markets);
+        top_tracks["items"] = (0..tracks)
+            .map(|index| {
+                let mut track = track.clone();
+                track["name"] = format!("Track {:}", index).into();
+                track["id"] = format!("{:022}", index).into();
+                track["album"]["name"] = format!("Album {:}", index).into();
+                track["album"]["id"] = format!("{:022}", index * 7).into();
+                track["album"]["images"][0]["url"] =
+                    format!("https://i.scdn.co/image/{:040x}", index * 7919).into();
+                track
+            })
+            .collect();
+        top_tracks.to_string()
+    }
+
+    /// Sizes and hit costs of both ways of caching the top songs, run with
+    /// `cargo test compressed_top_songs -- --ignored --nocapture`. The parsed
+    /// songs keep only a count of the markets, so they're compared with the
+    /// compressed response as well as with the same response without markets.
+    #[test]
+    #[ignore]
+    fn compressed_top_songs_size_and_hit_cost() {
+        const HITS: u32 = 1000;
+        let body = top_tracks_with_markets(32);
+        let items = serde_json::from_str::<TopItems>(&body).unwrap().items;
+        let parsed = CachedTopSongs::Parsed(items.clone());
+        let compressed = CachedTopSongs::Compressed(cache::compress(body.as_bytes()));
+        let CachedTopSongs::Compressed(compressed_bytes) = &compressed else {
+            unreachable!()
+        };
+        let mut without_markets: Value = serde_json::from_str(&body).unwrap();
+        for item in without_markets["items"].as_array_mut().unwrap() {
+            item.as_object_mut().unwrap().remove("available_markets");
+            item["album"]
+                .as_object_mut()
+                .unwrap()
+                .remove("available_markets");
+        }
+
+        println!("response: {:} bytes", body.len());
+        println!(
+            "parsed songs as json: {:} bytes",
+            serde_json::to_vec(&items).unwrap().len()
+        );
+        println!("compressed response: {:} bytes", compressed_bytes.len());
+        println!(
+            "compressed response without markets: {:} bytes",
+            cache::compress(without_markets.to_string().as_bytes()).len()
+        );
+        for (name, cached) in [("parsed", &parsed), ("compressed", &compressed)] {
+            let started_at = std::time::Instant::now();
+            for _ in 0..HITS {
+                assert_eq!(cached.items().unwrap().len(), 32);
+            }
+            println!("{:} hit: {:?}", name, started_at.elapsed() / HITS);
+        }
+    }
+
+    /// Requests sharing a `Spot` behind a lock like the routes' `SharedState`
+    #[tokio::test]
+    async fn concurrent_current_song_misses_ask_spotify_once() {
+        let song_requests = Arc::new(AtomicUsize::new(0));
+        let requests = song_requests.clone();
+        let url = mock_server(move |_| {
+            Router::new().route(
+                "/me/player/currently-playing",
+                get(move || {
+                    requests.fetch_add(1, Ordering::SeqCst);
+                    async {
+                        tokio::time::sleep(Duration::from_millis(100)).await;
+                        include_str!("../reference/spotify/currently-playing-relinked.json")
+                    }
+                }),
+            )
+        });
+        let spot = Arc::new(tokio::sync::Mutex::new(mock_spot(&url)));
+        let current_song = |spot: Arc<tokio::sync::Mutex<Spot>>| async move {
+            spot.lock()
+                .await
+                .get_current_song(&[AdditionalType::Track])
+                .await
+        };
+
+        let (first, second) = tokio::join!(
+            tokio::spawn(current_song(spot.clone())),
+            tokio::spawn(current_song(spot.clone()))
+        );
+
+        assert!(matches!(first.unwrap(), Ok(Some(_))));
+        assert!(matches!(second.unwrap(), Ok(Some(_))));
+        assert_eq!(song_requests.load(Ordering::SeqCst), 1);
+    }
+
+    /// Counts the token refreshes, answering them with `status` and `body` until
+    /// the `failures` ran out and with a fresh token after
+    fn token_answering(
+        refreshes: Arc<AtomicUsize>,
+        failures: usize,
+        status: StatusCode,
+        body: Value,
+    ) -> String {
+        mock_server(move |_| {
+            Router::new().route(
+                "/api/token",
+                post(move || {
+                    let refresh = refreshes.fetch_add(1, Ordering::SeqCst);
+                    async move {
+                        if refresh < failures {
+                            return (status, Json(body)).into_response();
+                        }
+                        Json(serde_json::json!({
+                            "access_token": "fresh-token",
+                            "token_type": "Bearer",
+                            "expires_in": 3600,
+                            "scope": "user-read-playback-state"
+                        }))
+                        .into_response()
+                    }
+                }),
+            )
+        })
+    }
+
+    #[tokio::test]
+    async fn failed_token_refreshes_are_retried() {
+        let refreshes = Arc::new(AtomicUsize::new(0));
+        let url = token_answering(
+            refreshes.clone(),
+            1,
+            StatusCode::INTERNAL_SERVER_ERROR,
+            serde_json::json!({ "error": "server_error" }),
+        );
+        let mut spot = mock_spot(&url);
+
+        let refreshed = spot.get_token().await;
+
+        assert!(refreshed.is_ok());
+        assert_eq!(spot.token, "fresh-token");
+        assert_eq!(refreshes.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn revoked_refresh_tokens_are_not_retried() {
+        let refreshes = Arc::new(AtomicUsize::new(0));
+        let url = token_answering(
+            refreshes.clone(),
+            usize::MAX,
+            StatusCode::BAD_REQUEST,
+            serde_json::json!({ "error": "invalid_grant" }),
+        );
+        let mut spot = mock_spot(&url);
+        let started_at = std::time::Instant::now();
+
+        let refreshed = spot.get_token().await;
+
+        assert!(matches!(refreshed, Err(SpotError::RefreshTokenRevoked)));
+        assert_eq!(refreshes.load(Ordering::SeqCst), 1);
+        assert!(started_at.elapsed() < TOKEN_REFRESH_BACKOFF);
+    }
+
+    #[tokio::test]
+    async fn expired_tokens_are_refreshed_and_the_request_retried() {
+        let device_requests = Arc::new(AtomicUsize::new(0));
+        let requests = device_requests.clone();
+        let url = mock_server(move |_| {
+            Router::new()
+                .route(
+                    "/api/token",
+                    post(|| async {
+                        Json(serde_json::json!({
+                            "access_token": "fresh-token",
+                            "token_type": "Bearer",
+                            "expires_in": 3600,
+                            "scope": "user-read-playback-state"
+                        }))
+                    }),
+                )
+                .route(
+                    "/me/player/devices",
+                    get(move |headers: axum::http::HeaderMap| {
+                        requests.fetch_add(1, Ordering::SeqCst);
+                        let authorization = headers[axum::http::header::AUTHORIZATION].clone();
+                        async move {
+                            if authorization != "Bearer fresh-token" {
+                                return StatusCode::UNAUTHORIZED.into_response();
+                            }
+                            Json(serde_json::json!({ "devices": [] })).into_response()
+                        }
+                    }),
+                )
+        });
+        let mut spot = mock_spot(&url);
+        spot.token = "revoked-token".into();
+
+        let devices = spot.get_devices().await;
+
+        assert!(matches!(devices.as_deref(), Ok([])));
+        assert_eq!(spot.token, "fresh-token");
+        assert_eq!(spot.scopes, vec!["user-read-playback-state"]);
+        assert_eq!(device_requests.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn playlist_track_ids_are_read_from_the_trimmed_response() {
+        let queries = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let recorded = queries.clone();
+        let url = mock_server(move |_| {
+            Router::new().route(
+                "/playlists/playlist/tracks",
+                get(move |query: axum::extract::RawQuery| {
+                    recorded.lock().unwrap().push(query.0.unwrap_or_default());
+                    async { include_str!("../reference/spotify/playlist-track-ids.json") }
+                }),
+            )
+        });
+        let mut spot = mock_spot(&url);
+
+        let track_ids = spot.get_playlist_track_ids("playlist").await.unwrap();
+
+        assert_eq!(
+            track_ids,
+            HashSet::from([
+                "3n3Ppam7vgaVa1iaRUc9Lp".to_string(),
+                "7ouMYWpwJ422jRcDASZB7P".to_string()
+            ])
+        );
+        assert!(queries.lock().unwrap()[0].contains("fields=next,items(track(id))"));
+    }
+
+    #[tokio::test]
+    async fn fetch_all_pages_follows_next_links() {
+        let requests = Arc::new(AtomicUsize::new(0));
+        let url = mock_server(two_pages(requests.clone()));
+        let mut spot = mock_spot(&url);
+
+        let numbers = spot
+            .fetch_all_pages(
+                format!("{:}/numbers", url),
+                usize::MAX,
+                |page: Page<u32>| page,
+            )
+            .await;
+
+        assert_eq!(numbers, Ok(vec![1, 2, 3]));
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn fetch_all_pages_stops_at_the_limit() {
+        let requests = Arc::new(AtomicUsize::new(0));
+        let url = mock_server(two_pages(requests.clone()));
+        let mut spot = mock_spot(&url);
+
+        let numbers = spot
+            .fetch_all_pages(format!("{:}/numbers", url), 1, |page: Page<u32>| page)
+            .await;
+
+        assert_eq!(numbers, Ok(vec![1]));
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn fetch_all_pages_stops_at_max_pages() {
+        let requests = Arc::new(AtomicUsize::new(0));
+        let url = mock_server(two_pages(requests.clone()));
+        let mut spot = mock_spot(&url);
+        spot.max_pages = 1;
+
+        let numbers = spot
+            .fetch_all_pages(
+                format!("{:}/numbers", url),
+                usize::MAX,
+                |page: Page<u32>| page,
+            )
+            .await;
+
+        assert_eq!(numbers, Ok(vec![1, 2]));
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+    }
+
+    fn track(current_song: &CurrentSong) -> &Item {
+        match &current_song.item {
+            PlayingItem::Track(item) => item,
+            _ => panic!("Expected a track"),
+        }
+    }
+
+    #[test]
+    fn local_files_parse_without_an_id() {
+        let current_song: CurrentSong = serde_json::from_str(include_str!(
+            "../reference/spotify/currently-playing-local-file.json"
+        ))
+        .unwrap();
+        let item = track(&current_song);
+
+        assert!(item.is_local);
+        assert_eq!(item.id, None);
+        assert_eq!(
+            item.uri.as_deref(),
+            Some("spotify:local:Finn:Demos:untitled+demo:184")
+        );
+        assert_eq!(item.external_urls.spotify, None);
+        assert_eq!(item.key(), "untitled demo");
+    }
+
+    #[test]
+    fn current_song_ttl_expires_right_after_the_predicted_track_change() {
+        assert_eq!(current_song_ttl(TEN_SECONDS + 1), TEN_SECONDS);
+        assert_eq!(current_song_ttl(TEN_SECONDS), TEN_SECONDS);
+        assert_eq!(
+            current_song_ttl(TEN_SECONDS - 1),
+            TEN_SECONDS - 1 + TRACK_CHANGE_GRACE
+        );
+    }
+
+    #[test]
+    fn current_song_ttl_never_drops_below_the_floor() {
+        assert_eq!(current_song_ttl(0), MIN_CURRENT_SONG_TTL);
+        assert_eq!(current_song_ttl(-500), MIN_CURRENT_SONG_TTL);
+        assert_eq!(
+            current_song_ttl(MIN_CURRENT_SONG_TTL - TRACK_CHANGE_GRACE),
+            MIN_CURRENT_SONG_TTL
+        );
+    }
 }
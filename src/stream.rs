@@ -0,0 +1,143 @@
+//! Server sent events pushing the current song, for frontends that only read.
+
+use std::{
+    convert::Infallible,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use axum::{
+    body,
+    http::StatusCode,
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse, Response,
+    },
+    Extension,
+};
+use futures_util::{stream, Stream};
+use tracing::{info, instrument};
+
+use crate::{
+    spotify::{AdditionalType, CurrentSong},
+    SharedState,
+};
+
+pub const PUSH_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Identifies what's worth pushing, the song and whether it's playing.
+/// Clients extrapolate the progress themselves.
+pub fn change_key(song: &Option<CurrentSong>) -> Option<(String, bool)> {
+    song.as_ref().map(|song| {
+        (
+            serde_json::to_string(&song.item).unwrap_or_default(),
+            song.is_playing,
+        )
+    })
+}
+
+/// Pushes a `currentSong` event whenever the current song changes. Idle
+/// connections get `: keepalive` comments, which event parsers skip, so
+/// proxies don't close them.
+#[instrument(skip(state, keep_alive, clients))]
+pub async fn handle(
+    Extension(state): Extension<SharedState>,
+    Extension(keep_alive): Extension<SseKeepAlive>,
+    Extension(clients): Extension<StreamClients>,
+) -> Response {
+    let Some(client) = clients.connect() else {
+        return Response::builder()
+            .status(StatusCode::SERVICE_UNAVAILABLE)
+            .body(body::Empty::new())
+            .unwrap()
+            .into_response();
+    };
+
+    info!("Stream connected");
+    Sse::new(events(state, client))
+        .keep_alive(KeepAlive::new().interval(keep_alive.0).text(" keepalive"))
+        .into_response()
+}
+
+/// The client is counted until the stream is dropped on disconnect
+fn events(
+    state: SharedState,
+    client: StreamClient,
+) -> impl Stream<Item = Result<Event, Infallible>> {
+    stream::unfold(
+        (state, client, None, tokio::time::interval(PUSH_INTERVAL)),
+        |(state, client, mut last_pushed, mut interval)| async move {
+            loop {
+                interval.tick().await;
+                let song = state
+                    .lock()
+                    .await
+                    .spot
+                    .get_current_song(&[AdditionalType::Track, AdditionalType::Episode])
+                    .await;
+                let Ok(song) = song else {
+                    continue;
+                };
+
+                let pushed = change_key(&song);
+                if last_pushed.as_ref() == Some(&pushed) {
+                    continue;
+                }
+                last_pushed = Some(pushed);
+
+                let event = Event::default()
+                    .event("currentSong")
+                    .json_data(&song)
+                    .unwrap_or_default();
+                return Some((Ok(event), (state, client, last_pushed, interval)));
+            }
+        },
+    )
+}
+
+/// Interval of the keepalive comments
+#[derive(Clone, Copy)]
+pub struct SseKeepAlive(pub Duration);
+
+/// Open `/stream` and `/ws` connections, each one polls the current song so
+/// they're capped at `max`
+#[derive(Clone)]
+pub struct StreamClients {
+    connected: Arc<AtomicUsize>,
+    max: usize,
+}
+
+impl StreamClients {
+    pub fn new(max: usize) -> Self {
+        Self {
+            connected: Arc::new(AtomicUsize::new(0)),
+            max,
+        }
+    }
+
+    /// Counts a connection until the returned guard is dropped, `None` when
+    /// there are `max` connections already
+    pub fn connect(&self) -> Option<StreamClient> {
+        self.connected
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |connected| {
+                (connected < self.max).then_some(connected + 1)
+            })
+            .ok()
+            .map(|_| StreamClient(self.connected.clone()))
+    }
+
+    pub fn count(&self) -> usize {
+        self.connected.load(Ordering::SeqCst)
+    }
+}
+
+pub struct StreamClient(Arc<AtomicUsize>);
+
+impl Drop for StreamClient {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
@@ -0,0 +1,54 @@
+use std::{path::Path, time::Duration};
+
+use axum_server::tls_rustls::RustlsConfig;
+use tracing::{error, info};
+
+const WATCH_INTERVAL: Duration = Duration::from_secs(60);
+
+pub struct TlsPaths {
+    pub cert_path: String,
+    pub key_path: String,
+}
+
+/// Loads the certificate and key, panicking when they can't be read or parsed
+pub async fn load(paths: &TlsPaths) -> RustlsConfig {
+    match RustlsConfig::from_pem_file(&paths.cert_path, &paths.key_path).await {
+        Ok(config) => config,
+        Err(err) => panic!(
+            "Could not load TLS certificate {:} with key {:}: {:}",
+            paths.cert_path, paths.key_path, err
+        ),
+    }
+}
+
+/// Reloads the certificate and key whenever either file changed, so renewed
+/// certificates are picked up without a restart. A failed reload keeps serving
+/// the previous certificate.
+pub fn watch(config: RustlsConfig, paths: TlsPaths) {
+    tokio::spawn(async move {
+        let mut loaded_at = modified_at(&paths);
+        loop {
+            tokio::time::sleep(WATCH_INTERVAL).await;
+            let modified = modified_at(&paths);
+            if modified == loaded_at {
+                continue;
+            }
+
+            match config
+                .reload_from_pem_file(&paths.cert_path, &paths.key_path)
+                .await
+            {
+                Ok(_) => {
+                    info!("Reloaded TLS certificate");
+                    loaded_at = modified;
+                }
+                Err(err) => error!(%err, "Could not reload TLS certificate"),
+            }
+        }
+    });
+}
+
+fn modified_at(paths: &TlsPaths) -> [Option<std::time::SystemTime>; 2] {
+    [&paths.cert_path, &paths.key_path]
+        .map(|path| Path::new(path).metadata().and_then(|m| m.modified()).ok())
+}
@@ -0,0 +1,206 @@
+//! WebSocket pushing the current song and taking player commands, so
+//! interactive frontends need a single connection.
+
+use axum::{
+    body,
+    extract::{
+        ws::{Message, WebSocket},
+        Query, WebSocketUpgrade,
+    },
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    Extension,
+};
+use serde::{Deserialize, Serialize};
+use tracing::{info, instrument};
+
+use crate::{
+    is_authorized,
+    spotify::{AdditionalType, CurrentSong, MediaState},
+    stream::{change_key, StreamClients, PUSH_INTERVAL},
+    SharedState,
+};
+
+#[derive(Deserialize, Debug)]
+pub struct WsQuery {
+    // Browsers can't set headers on the upgrade request
+    token: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(tag = "command", rename_all = "camelCase")]
+enum Command {
+    Play,
+    Pause,
+    Next,
+    Previous,
+    #[serde(rename_all = "camelCase")]
+    Seek {
+        position_ms: i64,
+    },
+}
+
+#[derive(Serialize, Debug)]
+#[serde(tag = "type", rename_all = "camelCase")]
+enum Event<'a> {
+    CurrentSong { song: &'a Option<CurrentSong> },
+    CommandResult { ok: bool },
+    Error { message: &'static str },
+}
+
+#[instrument(skip(ws, state, clients, headers))]
+pub async fn handle(
+    ws: WebSocketUpgrade,
+    Query(query): Query<WsQuery>,
+    Extension(state): Extension<SharedState>,
+    Extension(clients): Extension<StreamClients>,
+    headers: HeaderMap,
+) -> Response {
+    let authorized = {
+        let state = state.lock().await;
+        is_authorized(&headers, &state.token) || query.token.as_deref() == Some(&state.token)
+    };
+    // Rejected before taking a slot, so unauthorized attempts can't crowd out clients
+    if !authorized {
+        return Response::builder()
+            .status(StatusCode::UNAUTHORIZED)
+            .body(body::Empty::new())
+            .unwrap()
+            .into_response();
+    }
+
+    let Some(client) = clients.connect() else {
+        return Response::builder()
+            .status(StatusCode::SERVICE_UNAVAILABLE)
+            .body(body::Empty::new())
+            .unwrap()
+            .into_response();
+    };
+
+    ws.on_upgrade(move |socket| async move {
+        session(socket, state).await;
+        drop(client);
+    })
+}
+
+async fn session(mut socket: WebSocket, state: SharedState) {
+    info!("WebSocket connected");
+    let mut interval = tokio::time::interval(PUSH_INTERVAL);
+    let mut last_pushed = None;
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {
+                let song = state
+                    .lock()
+                    .await
+                    .spot
+                    .get_current_song(&[AdditionalType::Track, AdditionalType::Episode])
+                    .await;
+                let Ok(song) = song else {
+                    continue;
+                };
+
+                let pushed = change_key(&song);
+                if last_pushed.as_ref() == Some(&pushed) {
+                    continue;
+                }
+
+                if send(&mut socket, &Event::CurrentSong { song: &song }).await.is_err() {
+                    break;
+                }
+                last_pushed = Some(pushed);
+            }
+            message = socket.recv() => {
+                let text = match message {
+                    Some(Ok(Message::Text(text))) => text,
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                    Some(Ok(_)) => continue,
+                };
+
+                let event = match serde_json::from_str::<Command>(&text) {
+                    Ok(command) => run(&state, command).await,
+                    Err(_) => Event::Error {
+                        message: "Unknown command",
+                    },
+                };
+                if send(&mut socket, &event).await.is_err() {
+                    break;
+                }
+            }
+        }
+    }
+    info!("WebSocket disconnected");
+}
+
+#[instrument(skip(state))]
+async fn run(state: &SharedState, command: Command) -> Event<'static> {
+    let state = &mut state.lock().await;
+    if state.read_only {
+        return Event::Error {
+            message: "Server is read only",
+        };
+    }
+
+    info!("Running WebSocket command");
+    let result = match command {
+        Command::Play => state.spot.update_player_state(MediaState::Play).await,
+        Command::Pause => state.spot.update_player_state(MediaState::Pause).await,
+        Command::Next => state.spot.update_player_state(MediaState::Next).await,
+        Command::Previous => state.spot.update_player_state(MediaState::Previous).await,
+        Command::Seek { position_ms } if position_ms >= 0 => {
+            state.spot.seek(position_ms, None).await
+        }
+        Command::Seek { .. } => {
+            return Event::Error {
+                message: "positionMs must not be negative",
+            }
+        }
+    };
+
+    Event::CommandResult { ok: result.is_ok() }
+}
+
+async fn send(socket: &mut WebSocket, event: &Event<'_>) -> Result<(), axum::Error> {
+    let text = serde_json::to_string(event).unwrap_or_default();
+    socket.send(Message::Text(text)).await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+
+    use axum::{http::header, routing::get, Router};
+
+    use super::*;
+    use crate::tests::test_state;
+
+    #[tokio::test]
+    async fn unauthorized_upgrades_are_rejected_without_a_slot() {
+        let clients = StreamClients::new(1);
+        let app = Router::new()
+            .route("/ws", get(handle))
+            .layer(Extension(test_state()))
+            .layer(Extension(clients.clone()));
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{:}/ws?token=wrong", listener.local_addr().unwrap());
+        tokio::spawn(
+            axum::Server::from_tcp(listener)
+                .unwrap()
+                .serve(app.into_make_service()),
+        );
+
+        let response = reqwest::Client::new()
+            .get(&url)
+            .header(header::CONNECTION, "upgrade")
+            .header(header::UPGRADE, "websocket")
+            .header(header::SEC_WEBSOCKET_VERSION, "13")
+            .header(header::SEC_WEBSOCKET_KEY, "dGhlIHNhbXBsZSBub25jZQ==")
+            .send()
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), reqwest::StatusCode::UNAUTHORIZED);
+        assert_eq!(clients.count(), 0);
+        assert!(clients.connect().is_some());
+    }
+}